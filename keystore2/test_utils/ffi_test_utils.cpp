@@ -726,6 +726,39 @@ CxxResult getValueFromAttestRecord(rust::Vec<rust::u8> cert_buf, int32_t tag,
         return cxx_result;
     }
 
+    if (auth_tag == aidl::android::hardware::security::keymint::Tag::OS_VERSION ||
+        auth_tag == aidl::android::hardware::security::keymint::Tag::OS_PATCHLEVEL) {
+        // Software implementations report these in the software enforced list, so fall back to it
+        // if the tag is not hardware enforced.
+        aidl::android::hardware::security::keymint::KeyParameter param;
+        int pos = att_hw_enforced.find(auth_tag);
+        if (pos != -1) {
+            param = att_hw_enforced[pos];
+        } else {
+            pos = att_sw_enforced.find(auth_tag);
+            if (pos == -1) {
+                LOG(ERROR) << "getValueFromAttestRecord - OS version or patch level missing.";
+                cxx_result.error = true;
+                return cxx_result;
+            }
+            param = att_sw_enforced[pos];
+        }
+        std::string val = std::to_string(
+            param.value
+                .get<aidl::android::hardware::security::keymint::KeyParameterValue::integer>());
+        std::move(val.begin(), val.end(), std::back_inserter(cxx_result.data));
+        return cxx_result;
+    }
+
+    if (auth_tag == aidl::android::hardware::security::keymint::Tag::RESET_SINCE_ID_ROTATION) {
+        // Boolean tag, report whether it is present in either list. The data is left empty if
+        // the tag is absent.
+        if (att_hw_enforced.find(auth_tag) != -1 || att_sw_enforced.find(auth_tag) != -1) {
+            cxx_result.data.push_back(1);
+        }
+        return cxx_result;
+    }
+
     int pos = att_hw_enforced.find(auth_tag);
     if (pos == -1) {
         LOG(ERROR) << "getValueFromAttestRecord - unsupported tag.";
@@ -739,6 +772,54 @@ CxxResult getValueFromAttestRecord(rust::Vec<rust::u8> cert_buf, int32_t tag,
     return cxx_result;
 }
 
+RootOfTrustResult getRootOfTrustFromAttestRecord(rust::Vec<rust::u8> cert_buf) {
+    RootOfTrustResult rot_result{};
+    rot_result.error = true;
+
+    uint8_t* cert_data = cert_buf.data();
+    int cert_data_size = cert_buf.size();
+
+    std::vector<uint8_t> cert_bytes;
+    cert_bytes.insert(cert_bytes.end(), cert_data, (cert_data + cert_data_size));
+
+    X509_Ptr cert(parseCertBlob(cert_bytes));
+    if (!cert.get()) {
+        LOG(ERROR) << "getRootOfTrustFromAttestRecord - Failed to allocate a memory for "
+                      "certificate";
+        return rot_result;
+    }
+
+    ASN1_OCTET_STRING* attest_rec = getAttestationRecord(cert.get());
+    if (!attest_rec) {
+        LOG(ERROR) << "getRootOfTrustFromAttestRecord - Error in getAttestationRecord: "
+                   << keymaster::TranslateLastOpenSslError();
+        return rot_result;
+    }
+
+    std::vector<uint8_t> verified_boot_key;
+    std::vector<uint8_t> verified_boot_hash;
+    aidl::android::hardware::security::keymint::VerifiedBoot verified_boot_state;
+    bool device_locked = false;
+
+    auto error = aidl::android::hardware::security::keymint::parse_root_of_trust(
+        attest_rec->data, attest_rec->length, &verified_boot_key, &verified_boot_state,
+        &device_locked, &verified_boot_hash);
+    if (error != aidl::android::hardware::security::keymint::ErrorCode::OK) {
+        LOG(ERROR) << "getRootOfTrustFromAttestRecord - Error in parse_root_of_trust: "
+                   << static_cast<int32_t>(error);
+        return rot_result;
+    }
+
+    std::move(verified_boot_key.begin(), verified_boot_key.end(),
+              std::back_inserter(rot_result.verified_boot_key));
+    std::move(verified_boot_hash.begin(), verified_boot_hash.end(),
+              std::back_inserter(rot_result.verified_boot_hash));
+    rot_result.verified_boot_state = static_cast<int32_t>(verified_boot_state);
+    rot_result.device_locked = device_locked;
+    rot_result.error = false;
+    return rot_result;
+}
+
 uint32_t getOsVersion() {
     return aidl::android::hardware::security::keymint::getOsVersion();
 }
@@ -11,6 +11,7 @@ CxxResult buildAsn1DerEncodedWrappedKeyDescription();
 bool performCryptoOpUsingKeystoreEngine(int64_t grant_id);
 CxxResult getValueFromAttestRecord(rust::Vec<rust::u8> cert_buf, int32_t tag,
                                    int32_t expected_sec_level);
+RootOfTrustResult getRootOfTrustFromAttestRecord(rust::Vec<rust::u8> cert_buf);
 uint32_t getOsVersion();
 uint32_t getOsPatchlevel();
 uint32_t getVendorPatchlevel();
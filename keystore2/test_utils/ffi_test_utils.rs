@@ -26,6 +26,14 @@ mod ffi {
         error: bool,
     }
 
+    struct RootOfTrustResult {
+        verified_boot_key: Vec<u8>,
+        verified_boot_hash: Vec<u8>,
+        verified_boot_state: i32,
+        device_locked: bool,
+        error: bool,
+    }
+
     unsafe extern "C++" {
         include!("ffi_test_utils.hpp");
         fn validateCertChain(cert_buf: Vec<u8>, cert_len: u32, strict_issuer_check: bool) -> bool;
@@ -42,6 +50,7 @@ mod ffi {
             tag: i32,
             expected_sec_level: i32,
         ) -> CxxResult;
+        fn getRootOfTrustFromAttestRecord(cert_buf: Vec<u8>) -> RootOfTrustResult;
         fn getOsVersion() -> u32;
         fn getOsPatchlevel() -> u32;
         fn getVendorPatchlevel() -> u32;
@@ -128,6 +137,89 @@ pub fn get_value_from_attest_record(
     Err(Error::AttestRecordGetValueFailed)
 }
 
+/// Extract the OS version and OS patch level from the attestation record in the given
+/// certificate. Returns `None` if the certificate has no attestation record or the record omits
+/// either of these fields.
+pub fn extract_os_version(cert_buf: &[u8]) -> Option<(u32, u32)> {
+    let get_integer = |tag: Tag| -> Option<u32> {
+        // The expected security level is not consulted for these tags, both the hardware and
+        // software enforced lists are searched.
+        let result =
+            ffi::getValueFromAttestRecord(cert_buf.to_vec(), tag.0, SecurityLevel::SOFTWARE.0);
+        if result.error || result.data.is_empty() {
+            return None;
+        }
+        std::str::from_utf8(&result.data).ok()?.parse::<u32>().ok()
+    };
+
+    Some((get_integer(Tag::OS_VERSION)?, get_integer(Tag::OS_PATCHLEVEL)?))
+}
+
+/// Check whether the attestation record in the given certificate contains
+/// `RESET_SINCE_ID_ROTATION`. Returns `None` if the certificate has no attestation record.
+pub fn extract_reset_since_rotation(cert_buf: &[u8]) -> Option<bool> {
+    let result = ffi::getValueFromAttestRecord(
+        cert_buf.to_vec(),
+        Tag::RESET_SINCE_ID_ROTATION.0,
+        SecurityLevel::SOFTWARE.0,
+    );
+    if result.error {
+        return None;
+    }
+    Some(!result.data.is_empty())
+}
+
+/// Verified boot state reported in the `RootOfTrust` of an attestation record.
+/// See `KeyCreationResult.aidl` for documentation of the `VerifiedBootState` values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifiedBoot {
+    /// Full chain of trust extends from the bootloader to the verified partitions.
+    Verified,
+    /// The boot partition is verified using a user-installed key.
+    SelfSigned,
+    /// The device does not verify the partitions, e.g. the bootloader is unlocked.
+    Unverified,
+    /// The device failed verification.
+    Failed,
+}
+
+/// Verified boot information from the `RootOfTrust` sequence of an attestation record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedBootState {
+    /// Verified boot state of the device.
+    pub state: VerifiedBoot,
+    /// Whether the bootloader is locked.
+    pub device_locked: bool,
+    /// Digest of the key used to verify the system image.
+    pub verified_boot_key: Vec<u8>,
+    /// Digest of all data protected by verified boot, empty if the attestation record
+    /// predates this field.
+    pub verified_boot_hash: Vec<u8>,
+}
+
+/// Extract the verified boot state from the `RootOfTrust` of the attestation record in the
+/// given certificate. Returns `None` if the certificate has no attestation record or the
+/// root of trust could not be parsed.
+pub fn extract_verified_boot_state(cert_buf: &[u8]) -> Option<VerifiedBootState> {
+    let result = ffi::getRootOfTrustFromAttestRecord(cert_buf.to_vec());
+    if result.error {
+        return None;
+    }
+    let state = match result.verified_boot_state {
+        0 => VerifiedBoot::Verified,
+        1 => VerifiedBoot::SelfSigned,
+        2 => VerifiedBoot::Unverified,
+        3 => VerifiedBoot::Failed,
+        _ => return None,
+    };
+    Some(VerifiedBootState {
+        state,
+        device_locked: result.device_locked,
+        verified_boot_key: result.verified_boot_key,
+        verified_boot_hash: result.verified_boot_hash,
+    })
+}
+
 /// Get OS Version
 pub fn get_os_version() -> u32 {
     ffi::getOsVersion()
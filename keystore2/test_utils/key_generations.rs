@@ -31,8 +31,8 @@ use android_hardware_security_keymint::aidl::android::hardware::security::keymin
 use android_system_keystore2::aidl::android::system::keystore2::{
     AuthenticatorSpec::AuthenticatorSpec, Authorization::Authorization,
     CreateOperationResponse::CreateOperationResponse, Domain::Domain,
-    IKeystoreSecurityLevel::IKeystoreSecurityLevel, KeyDescriptor::KeyDescriptor,
-    KeyMetadata::KeyMetadata, ResponseCode::ResponseCode,
+    IKeystoreSecurityLevel::IKeystoreSecurityLevel, IKeystoreService::IKeystoreService,
+    KeyDescriptor::KeyDescriptor, KeyMetadata::KeyMetadata, ResponseCode::ResponseCode,
 };
 
 use crate::authorizations::AuthSetBuilder;
@@ -54,6 +54,10 @@ pub const TARGET_SU_CTX: &str = "u:r:su:s0";
 /// Vold context
 pub const TARGET_VOLD_CTX: &str = "u:r:vold:s0";
 
+/// EC curves defined by KeyMint. `CURVE_25519` is only supported from KeyMint v2 onwards.
+pub const EC_CURVES: &[EcCurve] =
+    &[EcCurve::P_224, EcCurve::P_256, EcCurve::P_384, EcCurve::P_521, EcCurve::CURVE_25519];
+
 /// Allowed tags in generated/imported key authorizations.
 /// See hardware/interfaces/security/keymint/aidl/android/hardware/security/keymint/Tag.aidl for the
 /// list feature tags.
@@ -366,6 +370,9 @@ pub enum Error {
     /// Error code to indicate error in getting value from attest record.
     #[error("Failed to get value from attest record.")]
     AttestRecordGetValueFailed,
+    /// Error code to indicate that the device does not provide a secure clock.
+    #[error("Secure clock is not supported.")]
+    SecureClockUnsupported,
 }
 
 /// Keystore2 error mapping.
@@ -391,6 +398,26 @@ pub fn map_ks_error<T>(r: BinderResult<T>) -> Result<T, Error> {
     })
 }
 
+/// Build a key descriptor for the given domain, keeping only the fields that domain uses:
+///  - `APP` and `SELINUX` keys are addressed by namespace and alias, so `alias` must be set.
+///  - `GRANT` and `KEY_ID` keys are addressed by the grant or key id given in `nspace` alone.
+///  - `BLOB` keys are addressed by the key blob alone.
+pub fn make_key_descriptor(
+    domain: Domain,
+    nspace: i64,
+    alias: Option<String>,
+    blob: Option<Vec<u8>>,
+) -> KeyDescriptor {
+    match domain {
+        Domain::APP | Domain::SELINUX => {
+            assert!(alias.is_some(), "{:?} key descriptor requires an alias", domain);
+            KeyDescriptor { domain, nspace, alias, blob }
+        }
+        Domain::GRANT | Domain::KEY_ID => KeyDescriptor { domain, nspace, alias: None, blob: None },
+        _ => KeyDescriptor { domain, nspace, alias: None, blob },
+    }
+}
+
 /// Indicate whether the default device is KeyMint (rather than Keymaster).
 pub fn has_default_keymint() -> bool {
     binder::is_declared("android.hardware.security.keymint.IKeyMintDevice/default")
@@ -513,6 +540,47 @@ fn check_common_auths(authorizations: &[Authorization], expected_key_origin: Key
     }
 }
 
+/// Key characteristics grouped by the security level enforcing them.
+pub type KeyCharacteristics = Vec<(SecurityLevel, Vec<KeyParameter>)>;
+
+/// Load the key entry of the given key and return its characteristics grouped by security level.
+/// Security levels appear in the order they are first encountered in the key authorizations.
+pub fn get_key_characteristics(
+    keystore2: &binder::Strong<dyn IKeystoreService>,
+    key: &KeyDescriptor,
+) -> Result<KeyCharacteristics, Error> {
+    let key_entry_response = map_ks_error(keystore2.getKeyEntry(key))?;
+
+    let mut characteristics: KeyCharacteristics = Vec::new();
+    for auth in key_entry_response.metadata.authorizations {
+        match characteristics.iter_mut().find(|(sec_level, _)| *sec_level == auth.securityLevel) {
+            Some((_, params)) => params.push(auth.keyParameter),
+            None => characteristics.push((auth.securityLevel, vec![auth.keyParameter])),
+        }
+    }
+    Ok(characteristics)
+}
+
+/// Verify that the given key characteristics contain the given tag with the expected value.
+/// Returns the security level enforcing it, so that callers can verify that as well.
+pub fn assert_has_tag(
+    characteristics: &KeyCharacteristics,
+    tag: Tag,
+    expected_value: &KeyParameterValue,
+) -> SecurityLevel {
+    characteristics
+        .iter()
+        .find_map(|(sec_level, params)| {
+            params
+                .iter()
+                .any(|param| param.tag == tag && &param.value == expected_value)
+                .then_some(*sec_level)
+        })
+        .unwrap_or_else(|| {
+            panic!("{:?} with value {:?} not found in {:#?}", tag, expected_value, characteristics)
+        })
+}
+
 /// Get the key `Authorization` for the given auth `Tag`.
 pub fn get_key_auth(authorizations: &[Authorization], tag: Tag) -> Option<&Authorization> {
     let auths: Vec<&Authorization> =
@@ -525,6 +593,12 @@ pub fn get_key_auth(authorizations: &[Authorization], tag: Tag) -> Option<&Autho
     }
 }
 
+/// Get the security level enforcing the key's algorithm, i.e. the security level of the backend
+/// the key actually lives in.
+pub fn extract_security_level(authorizations: &[Authorization]) -> Option<SecurityLevel> {
+    get_key_auth(authorizations, Tag::ALGORITHM).map(|auth| auth.securityLevel)
+}
+
 /// Generate EC Key using given security level and domain with below key parameters and
 /// optionally allow the generated key to be attested with factory provisioned attest key using
 /// given challenge and application id -
@@ -717,12 +791,7 @@ pub fn generate_sym_key(
     }
 
     let key_metadata = sec_level.generateKey(
-        &KeyDescriptor {
-            domain: Domain::APP,
-            nspace: -1,
-            alias: Some(alias.to_string()),
-            blob: None,
-        },
+        &make_key_descriptor(Domain::APP, -1, Some(alias.to_string()), None),
         None,
         &gen_params,
         0,
@@ -756,12 +825,7 @@ pub fn generate_hmac_key(
         .digest(digest);
 
     let key_metadata = sec_level.generateKey(
-        &KeyDescriptor {
-            domain: Domain::APP,
-            nspace: -1,
-            alias: Some(alias.to_string()),
-            blob: None,
-        },
+        &make_key_descriptor(Domain::APP, -1, Some(alias.to_string()), None),
         None,
         &gen_params,
         0,
@@ -842,12 +906,7 @@ pub fn generate_ec_attestation_key(
         .attestation_challenge(att_challenge.to_vec());
 
     let attestation_key_metadata = sec_level.generateKey(
-        &KeyDescriptor {
-            domain: Domain::APP,
-            nspace: -1,
-            alias: Some(alias.to_string()),
-            blob: None,
-        },
+        &make_key_descriptor(Domain::APP, -1, Some(alias.to_string()), None),
         None,
         &gen_params,
         0,
@@ -1218,6 +1277,27 @@ pub fn import_wrapped_key(
     let unwrap_params =
         AuthSetBuilder::new().digest(Digest::SHA_2_256).padding_mode(PaddingMode::RSA_OAEP);
 
+    import_wrapped_key_with_masking_key(
+        sec_level,
+        alias,
+        wrapping_key_metadata,
+        wrapped_key,
+        None,
+        &unwrap_params,
+    )
+}
+
+/// Import wrapped key using given wrapping key, masking key and unwrapping parameters.
+/// The masking key is XORed with the encrypted transport key of the `SecureKeyWrapper`,
+/// Keystore uses an all-zero masking key if none is given.
+pub fn import_wrapped_key_with_masking_key(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    alias: Option<String>,
+    wrapping_key_metadata: &KeyMetadata,
+    wrapped_key: Option<Vec<u8>>,
+    masking_key: Option<&[u8]>,
+    unwrap_params: &AuthSetBuilder,
+) -> binder::Result<KeyMetadata> {
     let authenticator_spec: &[AuthenticatorSpec] = &[AuthenticatorSpec {
         authenticatorType: HardwareAuthenticatorType::NONE,
         authenticatorId: 0,
@@ -1226,8 +1306,8 @@ pub fn import_wrapped_key(
     let key_metadata = sec_level.importWrappedKey(
         &KeyDescriptor { domain: Domain::APP, nspace: -1, alias, blob: wrapped_key },
         &wrapping_key_metadata.key,
-        None,
-        &unwrap_params,
+        masking_key,
+        unwrap_params,
         authenticator_spec,
     )?;
 
@@ -1421,12 +1501,7 @@ pub fn generate_key(
     alias: &str,
 ) -> binder::Result<KeyMetadata> {
     let key_metadata = sec_level.generateKey(
-        &KeyDescriptor {
-            domain: Domain::APP,
-            nspace: -1,
-            alias: Some(alias.to_string()),
-            blob: None,
-        },
+        &make_key_descriptor(Domain::APP, -1, Some(alias.to_string()), None),
         None,
         gen_params,
         0,
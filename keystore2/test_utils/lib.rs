@@ -13,14 +13,23 @@
 // limitations under the License.
 
 //! Implements TempDir which aids in creating an cleaning up temporary directories for testing.
+//! Also provides access to the Keystore2 services.
 
 use std::fs::{create_dir, remove_dir_all};
 use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::{env::temp_dir, ops::Deref};
 
-use android_system_keystore2::aidl::android::system::keystore2::IKeystoreService::IKeystoreService;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, IKeystoreSecurityLevel::IKeystoreSecurityLevel,
+    IKeystoreService::IKeystoreService, KeyDescriptor::KeyDescriptor,
+    KeyEntryResponse::KeyEntryResponse,
+};
 use android_security_authorization::aidl::android::security::authorization::IKeystoreAuthorization::IKeystoreAuthorization;
+use android_security_maintenance::aidl::android::security::maintenance::IKeystoreMaintenance::IKeystoreMaintenance;
+use android_security_metrics::aidl::android::security::metrics::IKeystoreMetrics::IKeystoreMetrics;
 
 pub mod authorizations;
 pub mod ffi_test_utils;
@@ -29,6 +38,8 @@ pub mod run_as;
 
 static KS2_SERVICE_NAME: &str = "android.system.keystore2.IKeystoreService/default";
 static AUTH_SERVICE_NAME: &str = "android.security.authorization";
+static MAINTENANCE_SERVICE_NAME: &str = "android.security.maintenance";
+static METRICS_SERVICE_NAME: &str = "android.security.metrics";
 
 /// Represents the lifecycle of a temporary directory for testing.
 #[derive(Debug)]
@@ -123,3 +134,93 @@ pub fn get_keystore_service() -> binder::Strong<dyn IKeystoreService> {
 pub fn get_keystore_auth_service() -> binder::Strong<dyn IKeystoreAuthorization> {
     binder::get_interface(AUTH_SERVICE_NAME).unwrap()
 }
+
+/// Get Keystore maintenance service.
+pub fn get_keystore_maintenance_service() -> binder::Strong<dyn IKeystoreMaintenance> {
+    binder::get_interface(MAINTENANCE_SERVICE_NAME).unwrap()
+}
+
+/// Get Keystore metrics service.
+pub fn get_keystore_metrics_service() -> binder::Strong<dyn IKeystoreMetrics> {
+    binder::get_interface(METRICS_SERVICE_NAME).unwrap()
+}
+
+/// Keystore2 service handle which transparently reconnects if the service died, e.g. because
+/// keystore2 was restarted. A call failing with `DEAD_OBJECT` is retried once on a freshly
+/// acquired service handle.
+/// Note: Security level handles returned by `get_security_level` die with the service as well and
+/// have to be obtained again after a restart.
+pub struct KeystoreClient {
+    service: Mutex<binder::Strong<dyn IKeystoreService>>,
+}
+
+impl Default for KeystoreClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeystoreClient {
+    /// Connect to the Keystore2 service.
+    pub fn new() -> Self {
+        Self { service: Mutex::new(get_keystore_service()) }
+    }
+
+    /// Returns the current service handle.
+    pub fn service(&self) -> binder::Strong<dyn IKeystoreService> {
+        self.service.lock().unwrap().clone()
+    }
+
+    fn call<T>(
+        &self,
+        f: impl Fn(&binder::Strong<dyn IKeystoreService>) -> binder::Result<T>,
+    ) -> binder::Result<T> {
+        match f(&self.service()) {
+            Err(e) if e.transaction_error() == binder::StatusCode::DEAD_OBJECT => {
+                let service = binder::wait_for_interface::<dyn IKeystoreService>(KS2_SERVICE_NAME)
+                    .map_err(binder::Status::from)?;
+                *self.service.lock().unwrap() = service.clone();
+                f(&service)
+            }
+            result => result,
+        }
+    }
+
+    /// See `IKeystoreService::getSecurityLevel`.
+    pub fn get_security_level(
+        &self,
+        sec_level: SecurityLevel,
+    ) -> binder::Result<binder::Strong<dyn IKeystoreSecurityLevel>> {
+        self.call(|ks2| ks2.getSecurityLevel(sec_level))
+    }
+
+    /// See `IKeystoreService::getKeyEntry`.
+    pub fn get_key_entry(&self, key: &KeyDescriptor) -> binder::Result<KeyEntryResponse> {
+        self.call(|ks2| ks2.getKeyEntry(key))
+    }
+
+    /// See `IKeystoreService::deleteKey`.
+    pub fn delete_key(&self, key: &KeyDescriptor) -> binder::Result<()> {
+        self.call(|ks2| ks2.deleteKey(key))
+    }
+
+    /// See `IKeystoreService::listEntries`.
+    pub fn list_entries(&self, domain: Domain, nspace: i64) -> binder::Result<Vec<KeyDescriptor>> {
+        self.call(|ks2| ks2.listEntries(domain, nspace))
+    }
+
+    /// See `IKeystoreService::grant`.
+    pub fn grant(
+        &self,
+        key: &KeyDescriptor,
+        grantee_uid: i32,
+        access_vector: i32,
+    ) -> binder::Result<KeyDescriptor> {
+        self.call(|ks2| ks2.grant(key, grantee_uid, access_vector))
+    }
+
+    /// See `IKeystoreService::ungrant`.
+    pub fn ungrant(&self, key: &KeyDescriptor, grantee_uid: i32) -> binder::Result<()> {
+        self.call(|ks2| ks2.ungrant(key, grantee_uid))
+    }
+}
@@ -342,6 +342,78 @@ where
     }
 }
 
+/// A closure together with the identity, given as SELinux context, UID, and GID, that
+/// `run_as_many` executes it under.
+pub type RunAsSpec<'a, R> = (&'a str, Uid, Gid, Box<dyn FnOnce() -> R + Send>);
+
+/// Run each of the given closures in its own new process running with the identity given in its
+/// spec. All processes are started before any of them is waited for, so the closures run
+/// concurrently. The results are returned in the order of the given specs. If a child process
+/// does not exit with status `0`, e.g. because its closure panicked, the slot of that child
+/// holds the `WaitStatus` of the child instead of a result.
+///
+/// # Safety
+/// run_as_many runs the given closures in the client branch of fork. And it uses non
+/// async signal safe API. This means that calling this function in a multi threaded program
+/// yields undefined behavior in the child. As of this writing, it is safe to call this function
+/// from a Rust device test, because every test itself is spawned as a separate process.
+///
+/// # Safety Binder
+/// It is okay for the closures to use binder services, however, this does not work
+/// if the parent initialized libbinder already. So do not use binder outside of the closures
+/// in your test.
+pub unsafe fn run_as_many<R>(specs: Vec<RunAsSpec<R>>) -> Vec<Result<R, WaitStatus>>
+where
+    R: Serialize + DeserializeOwned,
+{
+    let children: Vec<(Pid, ChannelReader<R>)> = specs
+        .into_iter()
+        .map(|(se_context, uid, gid, f)| {
+            let se_context =
+                selinux::Context::new(se_context).expect("Unable to construct selinux::Context.");
+            let (reader, mut writer) = pipe_channel::<R>().expect("Failed to create pipe.");
+
+            // SAFETY: Our caller guarantees that the process only has a single thread, so calling
+            // non-async-signal-safe functions in the child is in fact safe.
+            match unsafe { fork() } {
+                Ok(ForkResult::Parent { child, .. }) => {
+                    drop(writer);
+                    (child, reader)
+                }
+                Ok(ForkResult::Child) => {
+                    drop(reader);
+
+                    // This will panic on error or insufficient privileges.
+                    transition(se_context, uid, gid);
+
+                    // Run the closure.
+                    let result = f();
+
+                    // Serialize the result of the closure.
+                    writer.send(&result);
+
+                    // Set exit status to `0`.
+                    std::process::exit(0);
+                }
+                Err(errno) => {
+                    panic!("Failed to fork: {:?}", errno);
+                }
+            }
+        })
+        .collect();
+
+    children
+        .into_iter()
+        .map(|(child, mut reader)| {
+            match waitpid(child, None).expect("Failed while waiting for child.") {
+                // Child exited successfully. Read the result from the pipe.
+                WaitStatus::Exited(_, 0) => Ok(reader.recv()),
+                status => Err(status),
+            }
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -402,6 +474,33 @@ mod test {
         assert_eq!(test_result, result);
     }
 
+    /// Tests that the results of all closures are returned in order and that a panicking closure
+    /// only affects its own slot.
+    #[test]
+    fn test_run_as_many() {
+        let specs: Vec<RunAsSpec<SomeResult>> = (0..3u32)
+            .map(|i| {
+                let f: Box<dyn FnOnce() -> SomeResult + Send> = Box::new(move || {
+                    assert_eq!(TARGET_UID, getuid());
+                    if i == 1 {
+                        panic!("Closure must panic.");
+                    }
+                    SomeResult { a: i, b: i.into(), c: format!("result_{}", i) }
+                });
+                (TARGET_CTX, TARGET_UID, TARGET_GID, f)
+            })
+            .collect();
+
+        // Safety: run_as_many must be called from a single threaded process.
+        // This device test is run as a separate single threaded process.
+        let results = unsafe { run_as_many(specs) };
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0], Ok(SomeResult { a: 0, b: 0, c: "result_0".to_owned() }));
+        assert!(results[1].is_err());
+        assert_eq!(results[2], Ok(SomeResult { a: 2, b: 2, c: "result_2".to_owned() }));
+    }
+
     #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
     enum PingPong {
         Ping,
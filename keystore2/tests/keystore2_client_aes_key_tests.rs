@@ -26,7 +26,8 @@ use keystore2_test_utils::{
 };
 
 use crate::keystore2_client_test_utils::{
-    perform_sample_sym_key_decrypt_op, perform_sample_sym_key_encrypt_op, SAMPLE_PLAIN_TEXT,
+    assert_no_cert_for_symmetric, delete_app_key, get_op_nonce, perform_sample_sym_key_decrypt_op,
+    perform_sample_sym_key_encrypt_op, test_alias, SAMPLE_PLAIN_TEXT,
 };
 
 /// Generate a AES key. Create encrypt and decrypt operations using the generated key.
@@ -475,3 +476,298 @@ fn keystore2_aes_key_op_fails_nonce_prohibited() {
     assert!(result.is_err());
     assert_eq!(Error::Km(ErrorCode::CALLER_NONCE_PROHIBITED), result.unwrap_err());
 }
+
+/// Generate a AES key with the given block mode and create an encrypt operation with it. For GCM
+/// provide AAD, then input data and then try to provide more AAD, which must be rejected because
+/// all AAD has to precede the input data. For non-AEAD modes try to provide any AAD, which must be
+/// rejected as well. Returns the error reported for the rejected `updateAad` call.
+fn assert_aad_ordering(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    block_mode: BlockMode,
+) -> Error {
+    let keystore2 = get_keystore_service();
+    let alias = test_alias(&format!("assert_aad_ordering_{}", block_mode.0));
+    let (min_mac_len, mac_len) = match block_mode {
+        BlockMode::GCM => (Some(128), Some(128)),
+        _ => (None, None),
+    };
+    let key_metadata = key_generations::generate_sym_key(
+        sec_level,
+        Algorithm::AES,
+        128,
+        &alias,
+        &PaddingMode::NONE,
+        &block_mode,
+        min_mac_len,
+    )
+    .unwrap();
+
+    let mut op_params = authorizations::AuthSetBuilder::new()
+        .purpose(KeyPurpose::ENCRYPT)
+        .padding_mode(PaddingMode::NONE)
+        .block_mode(block_mode);
+    if let Some(val) = mac_len {
+        op_params = op_params.mac_length(val);
+    }
+    let op_response = sec_level.createOperation(&key_metadata.key, &op_params, false).unwrap();
+    assert!(op_response.iOperation.is_some());
+    let op = op_response.iOperation.unwrap();
+
+    let result = if block_mode == BlockMode::GCM {
+        op.updateAad(b"early aad").unwrap();
+        op.update(SAMPLE_PLAIN_TEXT).unwrap();
+        key_generations::map_ks_error(op.updateAad(b"late aad"))
+    } else {
+        key_generations::map_ks_error(op.updateAad(b"aad"))
+    };
+    delete_app_key(&keystore2, &alias).unwrap();
+    assert!(result.is_err(), "updateAad unexpectedly accepted for {:?}", block_mode);
+    result.unwrap_err()
+}
+
+/// Try to provide AAD after input data to an AES-GCM operation and to provide AAD to non-AEAD
+/// AES operations. Test should fail to provide AAD with `INVALID_TAG` error code in all cases.
+#[test]
+fn keystore2_aes_update_aad_ordering() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    assert_eq!(Error::Km(ErrorCode::INVALID_TAG), assert_aad_ordering(&sec_level, BlockMode::GCM));
+
+    for block_mode in [BlockMode::ECB, BlockMode::CBC, BlockMode::CTR] {
+        assert_eq!(
+            Error::Km(ErrorCode::INVALID_TAG),
+            assert_aad_ordering(&sec_level, block_mode),
+            "{:?}",
+            block_mode
+        );
+    }
+}
+
+/// Generate an AES key with the given block mode, encrypt a zero-length message with it and
+/// decrypt the resulting cipher text again. CBC and ECB use PKCS7 padding, all other modes use no
+/// padding. The decrypted plain text must be empty. Returns the length of the cipher text, so that
+/// callers can verify it contains only the GCM tag or the CBC padding block.
+fn assert_empty_plaintext_roundtrip(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    block_mode: BlockMode,
+) -> usize {
+    let keystore2 = get_keystore_service();
+    let alias = test_alias(&format!("assert_empty_plaintext_roundtrip_{}", block_mode.0));
+    let (padding_mode, mac_len) = match block_mode {
+        BlockMode::GCM => (PaddingMode::NONE, Some(128)),
+        BlockMode::CBC | BlockMode::ECB => (PaddingMode::PKCS7, None),
+        _ => (PaddingMode::NONE, None),
+    };
+    let key_metadata = key_generations::generate_sym_key(
+        sec_level,
+        Algorithm::AES,
+        128,
+        &alias,
+        &padding_mode,
+        &block_mode,
+        mac_len,
+    )
+    .unwrap();
+
+    let mut op_params = authorizations::AuthSetBuilder::new()
+        .purpose(KeyPurpose::ENCRYPT)
+        .padding_mode(padding_mode)
+        .block_mode(block_mode);
+    if let Some(val) = mac_len {
+        op_params = op_params.mac_length(val);
+    }
+    let op_response = sec_level.createOperation(&key_metadata.key, &op_params, false).unwrap();
+    let mut nonce = op_response.parameters.as_ref().and_then(get_op_nonce);
+    let empty_plain_text: &[u8] = &[];
+    let cipher_text = op_response
+        .iOperation
+        .unwrap()
+        .finish(Some(empty_plain_text), None)
+        .unwrap()
+        .unwrap_or_default();
+
+    let plain_text = perform_sample_sym_key_decrypt_op(
+        sec_level,
+        &cipher_text,
+        padding_mode,
+        block_mode,
+        &mut nonce,
+        mac_len,
+        &key_metadata.key,
+    )
+    .unwrap();
+    delete_app_key(&keystore2, &alias).unwrap();
+
+    assert!(
+        plain_text.as_ref().map_or(true, |text| text.is_empty()),
+        "{:?}: expected empty plain text, got {:?}",
+        block_mode,
+        plain_text
+    );
+    cipher_text.len()
+}
+
+/// Encrypt and decrypt a zero-length message using AES keys with GCM, CTR and CBC-PKCS7 block
+/// modes. Test should decrypt an empty plain text in all modes. The cipher text should consist of
+/// only the tag for GCM, be empty for CTR and consist of a single padding block for CBC-PKCS7.
+#[test]
+fn keystore2_aes_empty_plaintext_roundtrip() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    for (block_mode, expected_cipher_text_len) in
+        [(BlockMode::GCM, 16), (BlockMode::CTR, 0), (BlockMode::CBC, 16)]
+    {
+        assert_eq!(
+            expected_cipher_text_len,
+            assert_empty_plaintext_roundtrip(&sec_level, block_mode),
+            "unexpected cipher text length for {:?}",
+            block_mode
+        );
+    }
+}
+
+/// Generate an AES-GCM key with `MIN_MAC_LENGTH` of 96 bits and perform encrypt and decrypt
+/// operations with `MAC_LENGTH` values below the minimum, at the minimum, at 128 bits and above
+/// 128 bits. Returns the MAC lengths together with the error they were rejected with, if any.
+fn assert_gcm_tag_length_bounds(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+) -> Vec<(i32, Result<(), Error>)> {
+    let keystore2 = get_keystore_service();
+    let alias = test_alias("assert_gcm_tag_length_bounds");
+    let min_mac_len = 96;
+    let key_metadata = key_generations::generate_sym_key(
+        sec_level,
+        Algorithm::AES,
+        128,
+        &alias,
+        &PaddingMode::NONE,
+        &BlockMode::GCM,
+        Some(min_mac_len),
+    )
+    .unwrap();
+
+    let results = [88, min_mac_len, 128, 136]
+        .into_iter()
+        .map(|mac_len| {
+            let mut nonce = None;
+            let result = key_generations::map_ks_error(perform_sample_sym_key_encrypt_op(
+                sec_level,
+                PaddingMode::NONE,
+                BlockMode::GCM,
+                &mut nonce,
+                Some(mac_len),
+                &key_metadata.key,
+            ))
+            .and_then(|cipher_text| {
+                key_generations::map_ks_error(perform_sample_sym_key_decrypt_op(
+                    sec_level,
+                    &cipher_text.unwrap(),
+                    PaddingMode::NONE,
+                    BlockMode::GCM,
+                    &mut nonce,
+                    Some(mac_len),
+                    &key_metadata.key,
+                ))
+            })
+            .map(|plain_text| assert_eq!(Some(SAMPLE_PLAIN_TEXT.to_vec()), plain_text));
+            (mac_len, result)
+        })
+        .collect();
+    delete_app_key(&keystore2, &alias).unwrap();
+    results
+}
+
+/// Try to perform AES-GCM operations with MAC lengths around the supported bounds. Test should
+/// reject a MAC length below `MIN_MAC_LENGTH` with `INVALID_MAC_LENGTH` and a MAC length above 128
+/// bits with `UNSUPPORTED_MAC_LENGTH` error code, while `MIN_MAC_LENGTH` and 128 bits succeed.
+#[test]
+fn keystore2_aes_gcm_tag_length_bounds() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    let results = assert_gcm_tag_length_bounds(&sec_level);
+    assert_eq!(
+        vec![
+            (88, Err(Error::Km(ErrorCode::INVALID_MAC_LENGTH))),
+            (96, Ok(())),
+            (128, Ok(())),
+            (136, Err(Error::Km(ErrorCode::UNSUPPORTED_MAC_LENGTH))),
+        ],
+        results
+    );
+}
+
+/// Generate an AES key with an attestation challenge. Symmetric keys can't be attested, so the
+/// key is expected to be generated without a certificate or certificate chain, the same as in
+/// `keystore2_attest_symmetric_key_fail_sys_error`. The key is deleted afterwards.
+fn assert_symmetric_attestation_ignored(sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>) {
+    let alias = test_alias("assert_symmetric_attestation_ignored");
+    let gen_params = authorizations::AuthSetBuilder::new()
+        .no_auth_required()
+        .algorithm(Algorithm::AES)
+        .purpose(KeyPurpose::ENCRYPT)
+        .purpose(KeyPurpose::DECRYPT)
+        .key_size(128)
+        .padding_mode(PaddingMode::NONE)
+        .block_mode(BlockMode::ECB)
+        .attestation_challenge(b"foo".to_vec());
+
+    let key_metadata = key_generations::generate_key(sec_level, &gen_params, &alias).unwrap();
+    assert_no_cert_for_symmetric(&key_metadata);
+    delete_app_key(&get_keystore_service(), &alias).unwrap();
+}
+
+/// Generate an AES key with `ATTESTATION_CHALLENGE`. Test should generate the key successfully,
+/// but without any certificate since symmetric keys can't be attested.
+#[test]
+fn keystore2_aes_key_attestation_ignored() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    assert_symmetric_attestation_ignored(&sec_level);
+}
+
+/// Generate an AES key authorized for CBC block mode only and try to create an operation with it
+/// requesting GCM block mode. Creating the operation must fail. Returns the error reported for it.
+fn assert_op_blockmode_must_match_key(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+) -> Error {
+    let alias = test_alias("assert_op_blockmode_must_match_key");
+    let key_metadata = key_generations::generate_sym_key(
+        sec_level,
+        Algorithm::AES,
+        128,
+        &alias,
+        &PaddingMode::NONE,
+        &BlockMode::CBC,
+        None,
+    )
+    .unwrap();
+
+    let result = key_generations::map_ks_error(perform_sample_sym_key_encrypt_op(
+        sec_level,
+        PaddingMode::NONE,
+        BlockMode::GCM,
+        &mut None,
+        Some(128),
+        &key_metadata.key,
+    ));
+    delete_app_key(&get_keystore_service(), &alias).unwrap();
+
+    result.expect_err("Operation with GCM block mode was created using a CBC key.")
+}
+
+/// Generate a AES-CBC key. Try to create an operation using generated key with GCM block mode.
+/// Test should fail to create an operation with `INCOMPATIBLE_BLOCK_MODE` error code.
+#[test]
+fn keystore2_aes_cbc_key_op_fails_gcm_blockmode() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    assert_eq!(
+        Error::Km(ErrorCode::INCOMPATIBLE_BLOCK_MODE),
+        assert_op_blockmode_must_match_key(&sec_level)
+    );
+}
@@ -12,7 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use nix::unistd::getuid;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::x509::X509;
 
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     Algorithm::Algorithm, BlockMode::BlockMode, Digest::Digest, EcCurve::EcCurve,
@@ -20,15 +23,18 @@ use android_hardware_security_keymint::aidl::android::hardware::security::keymin
     SecurityLevel::SecurityLevel, Tag::Tag,
 };
 use android_system_keystore2::aidl::android::system::keystore2::{
-    Domain::Domain, IKeystoreService::IKeystoreService, KeyDescriptor::KeyDescriptor,
-    ResponseCode::ResponseCode,
+    Domain::Domain, IKeystoreSecurityLevel::IKeystoreSecurityLevel,
+    IKeystoreService::IKeystoreService, KeyDescriptor::KeyDescriptor, ResponseCode::ResponseCode,
 };
 
 use keystore2_test_utils::{
     authorizations, get_keystore_service, key_generations, key_generations::Error,
 };
 
-use keystore2_test_utils::ffi_test_utils::{get_value_from_attest_record, validate_certchain};
+use keystore2_test_utils::ffi_test_utils::{
+    extract_os_version, extract_verified_boot_state, get_os_patchlevel, get_os_version,
+    get_value_from_attest_record, validate_certchain, VerifiedBoot,
+};
 
 use crate::{
     skip_device_id_attestation_tests, skip_test_if_no_app_attest_key_feature,
@@ -36,10 +42,46 @@ use crate::{
 };
 
 use crate::keystore2_client_test_utils::{
-    app_attest_key_feature_exists, device_id_attestation_feature_exists, get_attest_id_value,
-    is_second_imei_id_attestation_required, skip_device_id_attest_tests,
+    app_attest_key_feature_exists, assert_attestation_chains_to_rkp_root, attestation_cert_size,
+    delete_app_key, device_id_attestation_feature_exists, find_attestation_root,
+    get_attest_id_value, get_system_prop, is_second_imei_id_attestation_required,
+    skip_device_id_attest_tests, test_alias,
 };
 
+/// Generate RSA and EC attestation keys and try to use each of them to sign arbitrary data.
+/// Attestation keys must only be usable for attesting other keys, so creating a signing
+/// operation is expected to fail with error code `INCOMPATIBLE_PURPOSE`.
+fn assert_attest_key_cannot_sign_data(sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>) {
+    let att_challenge: &[u8] = b"foo";
+
+    for algo in [Algorithm::RSA, Algorithm::EC] {
+        let attestation_key_metadata =
+            key_generations::generate_attestation_key(sec_level, algo, att_challenge).unwrap();
+
+        let mut op_params = authorizations::AuthSetBuilder::new()
+            .purpose(KeyPurpose::SIGN)
+            .digest(Digest::SHA_2_256);
+        if algo == Algorithm::RSA {
+            op_params = op_params.padding_mode(PaddingMode::RSA_PKCS1_1_5_SIGN);
+        }
+
+        let result = key_generations::map_ks_error(sec_level.createOperation(
+            &attestation_key_metadata.key,
+            &op_params,
+            false,
+        ));
+        match result {
+            Ok(_) => panic!("{:?} attestation key was allowed to sign arbitrary data.", algo),
+            Err(e) => assert_eq!(
+                Error::Km(ErrorCode::INCOMPATIBLE_PURPOSE),
+                e,
+                "Unexpected error while signing with {:?} attestation key.",
+                algo
+            ),
+        }
+    }
+}
+
 /// Generate RSA and EC attestation keys and use them for signing RSA-signing keys.
 /// Test should be able to generate attestation keys and use them successfully.
 #[test]
@@ -61,7 +103,7 @@ fn keystore2_attest_rsa_signing_key_success() {
         validate_certchain(&cert_chain).expect("Error while validating cert chain.");
 
         // Create RSA signing key and use attestation key to sign it.
-        let sign_key_alias = format!("ks_attest_rsa_signing_key_{}", getuid());
+        let sign_key_alias = test_alias("keystore2_attest_rsa_signing_key_success");
         let sign_key_metadata = key_generations::generate_rsa_key(
             &sec_level,
             Domain::APP,
@@ -109,7 +151,7 @@ fn keystore2_attest_rsa_encrypt_key_success() {
         validate_certchain(&cert_chain).expect("Error while validating cert chain.");
 
         // Create RSA encrypt/decrypt key and use attestation key to sign it.
-        let decrypt_key_alias = format!("ks_attest_rsa_encrypt_key_{}", getuid());
+        let decrypt_key_alias = test_alias("keystore2_attest_rsa_encrypt_key_success");
         let decrypt_key_metadata = key_generations::generate_rsa_key(
             &sec_level,
             Domain::APP,
@@ -158,7 +200,7 @@ fn keystore2_attest_ec_key_success() {
         validate_certchain(&cert_chain).expect("Error while validating cert chain.");
 
         // Create EC key and use attestation key to sign it.
-        let ec_key_alias = format!("ks_ec_attested_test_key_{}", getuid());
+        let ec_key_alias = test_alias("keystore2_attest_ec_key_success");
         let ec_key_metadata = key_generations::generate_ec_256_attested_key(
             &sec_level,
             Some(ec_key_alias),
@@ -202,7 +244,7 @@ fn keystore2_attest_rsa_signing_key_with_ec_25519_key_success() {
     validate_certchain(&cert_chain).expect("Error while validating cert chain.");
 
     // Create RSA signing key and use attestation key to sign it.
-    let sign_key_alias = format!("ksrsa_attested_sign_test_key_{}", getuid());
+    let sign_key_alias = test_alias("keystore2_attest_rsa_signing_key_with_ec_25519_key_success");
     let sign_key_metadata = key_generations::generate_rsa_key(
         &sec_level,
         Domain::APP,
@@ -228,6 +270,18 @@ fn keystore2_attest_rsa_signing_key_with_ec_25519_key_success() {
     validate_certchain(&cert_chain).expect("Error while validating cert chain");
 }
 
+/// Generate RSA and EC attestation keys and try to sign data with them. Test should fail to
+/// create a signing operation with error code `INCOMPATIBLE_PURPOSE`.
+#[test]
+fn keystore2_attest_key_sign_data_fails_incompat_purpose() {
+    skip_test_if_no_app_attest_key_feature!();
+
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    assert_attest_key_cannot_sign_data(&sec_level);
+}
+
 /// Try to generate RSA attestation key with multiple purposes. Test should fail with error code
 /// `INCOMPATIBLE_PURPOSE` to generate an attestation key.
 #[test]
@@ -241,8 +295,10 @@ fn keystore2_generate_rsa_attest_key_with_multi_purpose_fail() {
     let padding = PaddingMode::RSA_PKCS1_1_5_SIGN;
     let key_size = 2048;
 
-    let attest_key_alias =
-        format!("ksrsa_attest_multipurpose_key_{}{}{}", getuid(), key_size, digest.0);
+    let attest_key_alias = test_alias(&format!(
+        "keystore2_generate_rsa_attest_key_with_multi_purpose_fail_{}_{}",
+        key_size, digest.0
+    ));
 
     let attest_gen_params = authorizations::AuthSetBuilder::new()
         .no_auth_required()
@@ -280,7 +336,7 @@ fn keystore2_ec_attest_key_with_multi_purpose_fail() {
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
 
-    let attest_key_alias = format!("ks_ec_attest_multipurpose_key_{}", getuid());
+    let attest_key_alias = test_alias("keystore2_ec_attest_key_with_multi_purpose_fail");
 
     let attest_gen_params = authorizations::AuthSetBuilder::new()
         .no_auth_required()
@@ -329,7 +385,7 @@ fn keystore2_attest_key_fails_missing_challenge() {
     validate_certchain(&cert_chain).expect("Error while validating cert chain.");
 
     // Try to attest RSA signing key without providing attestation challenge.
-    let sign_key_alias = format!("ksrsa_attested_test_key_missing_challenge{}", getuid());
+    let sign_key_alias = test_alias("keystore2_attest_key_fails_missing_challenge");
     let result = key_generations::map_ks_error(key_generations::generate_rsa_key(
         &sec_level,
         Domain::APP,
@@ -361,7 +417,8 @@ fn keystore2_attest_rsa_key_with_non_attest_key_fails_incompat_purpose_error() {
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
     let att_challenge: &[u8] = b"foo";
 
-    let alias = format!("non_attest_key_{}", getuid());
+    let alias =
+        test_alias("keystore2_attest_rsa_key_with_non_attest_key_fails_incompat_purpose_error");
     let non_attest_key_metadata = key_generations::generate_ec_p256_signing_key(
         &sec_level,
         Domain::APP,
@@ -372,7 +429,9 @@ fn keystore2_attest_rsa_key_with_non_attest_key_fails_incompat_purpose_error() {
     .unwrap();
 
     // Try to generate RSA signing key with non-attestation key to sign it.
-    let sign_key_alias = format!("ksrsa_attested_sign_test_key_non_attest_{}", getuid());
+    let sign_key_alias = test_alias(
+        "keystore2_attest_rsa_key_with_non_attest_key_fails_incompat_purpose_error_sign_key",
+    );
     let result = key_generations::map_ks_error(key_generations::generate_rsa_key(
         &sec_level,
         Domain::APP,
@@ -416,7 +475,7 @@ fn keystore2_attest_rsa_key_with_symmetric_key_fails_sys_error() {
     .unwrap();
 
     // Try to generate RSA signing key with symmetric key as attestation key.
-    let sign_key_alias = format!("ksrsa_attested_sign_test_key_sym_attest_{}", getuid());
+    let sign_key_alias = test_alias("keystore2_attest_rsa_key_with_symmetric_key_fails_sys_error");
     let result = key_generations::map_ks_error(key_generations::generate_rsa_key(
         &sec_level,
         Domain::APP,
@@ -469,7 +528,7 @@ fn keystore2_attest_symmetric_key_fail_sys_error() {
         .block_mode(BlockMode::ECB)
         .attestation_challenge(att_challenge.to_vec());
 
-    let alias = format!("ks_test_sym_key_attest_{}", getuid());
+    let alias = test_alias("keystore2_attest_symmetric_key_fail_sys_error");
     let aes_key_metadata = sec_level
         .generateKey(
             &KeyDescriptor { domain: Domain::APP, nspace: -1, alias: Some(alias), blob: None },
@@ -537,7 +596,7 @@ fn generate_attested_key_with_device_attest_ids(algorithm: Algorithm) {
 
     for (attest_id, value) in attest_id_params {
         // Create RSA/EC key and use attestation key to sign it.
-        let key_alias = format!("ks_attested_test_key_{}", getuid());
+        let key_alias = test_alias("generate_attested_key_with_device_attest_ids");
         let key_metadata =
             key_generations::map_ks_error(key_generations::generate_key_with_attest_id(
                 &sec_level,
@@ -612,7 +671,10 @@ fn keystore2_attest_key_fails_with_invalid_attestation_id() {
 
     for (attest_id, value) in attest_id_params {
         // Create EC key and use attestation key to sign it.
-        let ec_key_alias = format!("ks_ec_attested_test_key_fail_{}{}", getuid(), digest.0);
+        let ec_key_alias = test_alias(&format!(
+            "keystore2_attest_key_fails_with_invalid_attestation_id_{}",
+            digest.0
+        ));
         let result = key_generations::map_ks_error(key_generations::generate_key_with_attest_id(
             &sec_level,
             Algorithm::EC,
@@ -649,7 +711,9 @@ fn keystore2_attest_key_without_attestation_id_support_fails_with_cannot_attest_
     let attest_id_params = get_attestation_ids(&keystore2);
     for (attest_id, value) in attest_id_params {
         // Create RSA/EC key and use attestation key to sign it.
-        let key_alias = format!("ks_attested_test_key_{}", getuid());
+        let key_alias = test_alias(
+            "keystore2_attest_key_without_attestation_id_support_fails_with_cannot_attest_id",
+        );
         let result = key_generations::map_ks_error(key_generations::generate_key_with_attest_id(
             &sec_level,
             Algorithm::RSA,
@@ -666,3 +730,147 @@ fn keystore2_attest_key_without_attestation_id_support_fails_with_cannot_attest_
         assert_eq!(result.unwrap_err(), Error::Km(ErrorCode::CANNOT_ATTEST_IDS));
     }
 }
+
+/// Generate an attested EC key and extract the verified boot state from the `RootOfTrust` of its
+/// attestation record. Test should find the root of trust and the reported verified boot state
+/// should match the state the bootloader reported in `ro.boot.verifiedbootstate`.
+#[test]
+fn keystore2_attest_key_verified_boot_state_matches_device() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    let alias = test_alias("keystore2_attest_key_verified_boot_state_matches_device");
+    let key_metadata = key_generations::generate_ec_p256_signing_key(
+        &sec_level,
+        Domain::APP,
+        -1,
+        Some(alias.clone()),
+        Some(b"foo"),
+    )
+    .unwrap();
+
+    let boot_state = extract_verified_boot_state(key_metadata.certificate.as_ref().unwrap())
+        .expect("Failed to extract verified boot state from attestation record.");
+    assert!(!boot_state.verified_boot_key.is_empty());
+
+    let expected_state = match get_system_prop("ro.boot.verifiedbootstate").as_slice() {
+        b"green" => Some(VerifiedBoot::Verified),
+        b"yellow" => Some(VerifiedBoot::SelfSigned),
+        b"orange" => Some(VerifiedBoot::Unverified),
+        b"red" => Some(VerifiedBoot::Failed),
+        _ => None,
+    };
+    if let Some(expected_state) = expected_state {
+        assert_eq!(expected_state, boot_state.state);
+    }
+    if boot_state.state == VerifiedBoot::Verified {
+        assert!(boot_state.device_locked, "Verified boot state reported for unlocked device.");
+    }
+
+    delete_app_key(&keystore2, &alias).unwrap();
+}
+
+/// Generate an attested EC key and extract the OS version and OS patch level from its attestation
+/// record. Test should find both fields and they should match the values derived from the
+/// `ro.build.version.*` properties of the device.
+#[test]
+fn keystore2_attest_key_os_version_matches_device() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    let alias = test_alias("keystore2_attest_key_os_version_matches_device");
+    let key_metadata = key_generations::generate_ec_p256_signing_key(
+        &sec_level,
+        Domain::APP,
+        -1,
+        Some(alias.clone()),
+        Some(b"foo"),
+    )
+    .unwrap();
+
+    let (os_version, os_patchlevel) =
+        extract_os_version(key_metadata.certificate.as_ref().unwrap())
+            .expect("OS version or patch level missing in attestation record.");
+    assert_eq!(get_os_version(), os_version);
+    assert_eq!(get_os_patchlevel(), os_patchlevel);
+
+    delete_app_key(&keystore2, &alias).unwrap();
+}
+
+/// Generate an attested EC key and an AES key and measure the size of their certificates. Test
+/// should find the size of the EC key's certificates to match the sum of the DER lengths of the
+/// individual certificates in its chain, and no certificates for the AES key.
+#[test]
+fn keystore2_attestation_cert_size() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+    let alias = test_alias("keystore2_attestation_cert_size");
+
+    let key_metadata = key_generations::generate_ec_p256_signing_key(
+        &sec_level,
+        Domain::APP,
+        -1,
+        Some(alias.clone()),
+        Some(b"foo"),
+    )
+    .unwrap();
+
+    let mut cert_chain: Vec<u8> = Vec::new();
+    cert_chain.extend(key_metadata.certificate.as_ref().unwrap());
+    cert_chain.extend(key_metadata.certificateChain.as_ref().unwrap());
+    let certs = X509::stack_from_der(&cert_chain).unwrap();
+    assert!(certs.len() > 1);
+    let der_len: usize = certs.iter().map(|cert| cert.to_der().unwrap().len()).sum();
+    assert_eq!(der_len, attestation_cert_size(&key_metadata));
+    delete_app_key(&keystore2, &alias).unwrap();
+
+    let aes_alias = test_alias("keystore2_attestation_cert_size_aes");
+    let aes_key_metadata = key_generations::generate_sym_key(
+        &sec_level,
+        Algorithm::AES,
+        128,
+        &aes_alias,
+        &PaddingMode::NONE,
+        &BlockMode::ECB,
+        None,
+    )
+    .unwrap();
+    assert_eq!(0, attestation_cert_size(&aes_key_metadata));
+    delete_app_key(&keystore2, &aes_alias).unwrap();
+}
+
+/// Generate an attested EC key and look for the root of its attestation chain among a set of
+/// known roots. Test should find the root when the set contains the chain's root public key and
+/// should not find it when the set only contains an unrelated public key.
+#[test]
+fn keystore2_attestation_chain_root_lookup() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+    let alias = test_alias("keystore2_attestation_chain_root_lookup");
+
+    let key_metadata = key_generations::generate_ec_p256_signing_key(
+        &sec_level,
+        Domain::APP,
+        -1,
+        Some(alias.clone()),
+        Some(b"foo"),
+    )
+    .unwrap();
+
+    let mut cert_chain: Vec<u8> = Vec::new();
+    cert_chain.extend(key_metadata.certificate.as_ref().unwrap());
+    cert_chain.extend(key_metadata.certificateChain.as_ref().unwrap());
+    let certs = X509::stack_from_der(&cert_chain).unwrap();
+    let root_key = certs.last().unwrap().public_key().unwrap().public_key_to_der().unwrap();
+
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let unrelated_key =
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap().public_key_to_der().unwrap();
+
+    assert_eq!(
+        1,
+        assert_attestation_chains_to_rkp_root(&key_metadata, &[unrelated_key.clone(), root_key])
+    );
+    assert_eq!(None, find_attestation_root(&key_metadata, &[unrelated_key]));
+    delete_app_key(&keystore2, &alias).unwrap();
+}
@@ -15,20 +15,26 @@
 use std::time::SystemTime;
 
 use openssl::bn::{BigNum, MsbOption};
-use openssl::x509::X509NameBuilder;
+use openssl::hash::MessageDigest;
+use openssl::sign::Verifier;
+use openssl::x509::{X509NameBuilder, X509};
 
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     Algorithm::Algorithm, BlockMode::BlockMode, Digest::Digest, EcCurve::EcCurve,
-    ErrorCode::ErrorCode, KeyPurpose::KeyPurpose, PaddingMode::PaddingMode,
-    SecurityLevel::SecurityLevel, Tag::Tag,
+    ErrorCode::ErrorCode, KeyParameterValue::KeyParameterValue, KeyPurpose::KeyPurpose,
+    PaddingMode::PaddingMode, SecurityLevel::SecurityLevel, Tag::Tag,
 };
 
 use android_system_keystore2::aidl::android::system::keystore2::{
-    Domain::Domain, IKeystoreSecurityLevel::IKeystoreSecurityLevel, KeyDescriptor::KeyDescriptor,
-    KeyMetadata::KeyMetadata, ResponseCode::ResponseCode,
+    Domain::Domain, IKeystoreSecurityLevel::IKeystoreSecurityLevel,
+    IKeystoreService::IKeystoreService, KeyDescriptor::KeyDescriptor, KeyMetadata::KeyMetadata,
+    ResponseCode::ResponseCode,
 };
 
 use aconfig_android_hardware_biometrics_rust;
+use android_hardware_gatekeeper::aidl::android::hardware::gatekeeper::IGatekeeper::{
+    IGatekeeper, STATUS_OK,
+};
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     HardwareAuthToken::HardwareAuthToken,
     HardwareAuthenticatorType::HardwareAuthenticatorType
@@ -41,15 +47,21 @@ use keystore2_test_utils::{
 };
 
 use crate::keystore2_client_test_utils::{
-    app_attest_key_feature_exists, delete_app_key, perform_sample_asym_sign_verify_op,
-    perform_sample_hmac_sign_verify_op, perform_sample_sym_key_decrypt_op,
-    perform_sample_sym_key_encrypt_op, verify_certificate_serial_num,
-    verify_certificate_subject_name, SAMPLE_PLAIN_TEXT,
+    app_attest_key_feature_exists, assert_cert_subject_matches, assert_invalid_key_size_rejected,
+    delete_app_key, extract_operation_challenge, get_timestamp_token, log_test_skip,
+    perform_sample_asym_sign_verify_op, perform_sample_hmac_sign_verify_op,
+    perform_sample_sym_key_decrypt_op, perform_sample_sym_key_encrypt_op, test_alias,
+    verify_certificate_serial_num, verify_certificate_subject_name, verify_certificate_validity,
+    SAMPLE_PLAIN_TEXT,
 };
 
 use crate::{skip_test_if_no_app_attest_key_feature, skip_tests_if_keymaster_impl_present};
 
-use keystore2_test_utils::ffi_test_utils::get_value_from_attest_record;
+use keystore2_test_utils::ffi_test_utils::{
+    extract_reset_since_rotation, get_value_from_attest_record,
+};
+
+static GATEKEEPER_SERVICE_NAME: &str = "android.hardware.gatekeeper.IGatekeeper/default";
 
 fn gen_key_including_unique_id(
     sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
@@ -477,6 +489,8 @@ fn keystore2_gen_key_auth_early_boot_only_op_fail() {
 /// a key and verify the key characteristics. Test should be able to use the key successfully
 /// `MAX_USES_COUNT` times. After exceeding key usage `MAX_USES_COUNT` times
 /// subsequent attempts to use the key in test should fail with error code MAX_OPS_EXCEEDED.
+/// There is no way to reset the per-boot usage counter short of rebooting the device, so the
+/// key must stay unusable for the rest of this boot.
 #[test]
 fn keystore2_gen_key_auth_max_uses_per_boot() {
     let keystore2 = get_keystore_service();
@@ -502,6 +516,9 @@ fn keystore2_gen_key_auth_max_uses_per_boot() {
         MAX_USES_COUNT,
     )
     .unwrap();
+    let auth = key_generations::get_key_auth(&key_metadata.authorizations, Tag::MAX_USES_PER_BOOT)
+        .expect("MAX_USES_PER_BOOT is missing in key authorizations.");
+    assert_eq!(auth.keyParameter.value, KeyParameterValue::Integer(MAX_USES_COUNT));
 
     // Try to use the key one more time.
     let result = key_generations::map_ks_error(sec_level.createOperation(
@@ -511,9 +528,133 @@ fn keystore2_gen_key_auth_max_uses_per_boot() {
     ));
     assert!(result.is_err());
     assert_eq!(Error::Km(ErrorCode::KEY_MAX_OPS_EXCEEDED), result.unwrap_err());
+
+    // The usage counter is only reset on reboot, so the key must remain exhausted.
+    let (enforced_count, error) = use_key_until_exhausted(&sec_level, &key_metadata, 2);
+    assert_eq!(0, enforced_count, "Key was usable again after exceeding MAX_USES_PER_BOOT.");
+    assert_eq!(Some(Error::Km(ErrorCode::KEY_MAX_OPS_EXCEEDED)), error);
     delete_app_key(&keystore2, alias).unwrap();
 }
 
+/// Generate an EC key and load its characteristics grouped by security level. Test should find
+/// the requested algorithm, curve and digest enforced by the security level the key lives in.
+#[test]
+fn keystore2_gen_key_characteristics_at_key_security_level() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    let alias = test_alias("keystore2_gen_key_characteristics_at_key_security_level");
+    let key_metadata = key_generations::generate_ec_key(
+        &sec_level,
+        Domain::APP,
+        -1,
+        Some(alias.clone()),
+        EcCurve::P_256,
+        Digest::SHA_2_256,
+    )
+    .unwrap();
+
+    let characteristics =
+        key_generations::get_key_characteristics(&keystore2, &key_metadata.key).unwrap();
+    for (tag, value) in [
+        (Tag::ALGORITHM, KeyParameterValue::Algorithm(Algorithm::EC)),
+        (Tag::EC_CURVE, KeyParameterValue::EcCurve(EcCurve::P_256)),
+        (Tag::DIGEST, KeyParameterValue::Digest(Digest::SHA_2_256)),
+    ] {
+        assert_eq!(
+            key_metadata.keySecurityLevel,
+            key_generations::assert_has_tag(&characteristics, tag, &value)
+        );
+    }
+
+    delete_app_key(&keystore2, &alias).unwrap();
+}
+
+/// Generate a key with the given `gen_params` and verify that the given `tag`, which must be one
+/// of `gen_params`, is reported as enforced by the security level the key lives in rather than by
+/// software or keystore. Returns the security level whose enforcement list the tag landed in.
+fn assert_tag_hardware_enforced(
+    keystore2: &binder::Strong<dyn IKeystoreService>,
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    gen_params: &authorizations::AuthSetBuilder,
+    tag: Tag,
+) -> SecurityLevel {
+    assert!(
+        gen_params.iter().any(|param| param.tag == tag),
+        "{:?} is not part of the key parameters {:?}",
+        tag,
+        gen_params
+    );
+    let alias = test_alias(&format!("assert_tag_hardware_enforced_{}", tag.0));
+    let key_metadata = key_generations::generate_key(sec_level, gen_params, &alias).unwrap();
+
+    let characteristics =
+        key_generations::get_key_characteristics(keystore2, &key_metadata.key).unwrap();
+    let enforced_by = characteristics
+        .iter()
+        .find(|(_, params)| params.iter().any(|param| param.tag == tag))
+        .map(|(enforced_by, _)| *enforced_by);
+    delete_app_key(keystore2, &alias).unwrap();
+
+    let enforced_by =
+        enforced_by.unwrap_or_else(|| panic!("{:?} not found in {:#?}", tag, characteristics));
+    assert_eq!(
+        key_metadata.keySecurityLevel, enforced_by,
+        "{:?} was demoted to {:?} enforcement",
+        tag, enforced_by
+    );
+    enforced_by
+}
+
+/// Generate an EC key restricted with security-critical tags and verify that each of them is
+/// enforced by the security level the key lives in, i.e. that the backend does not demote their
+/// enforcement to software.
+#[test]
+fn keystore2_gen_key_security_critical_tags_hardware_enforced() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+    let gen_params = authorizations::AuthSetBuilder::new()
+        .no_auth_required()
+        .algorithm(Algorithm::EC)
+        .purpose(KeyPurpose::SIGN)
+        .purpose(KeyPurpose::VERIFY)
+        .digest(Digest::SHA_2_256)
+        .ec_curve(EcCurve::P_256);
+
+    for tag in [Tag::ALGORITHM, Tag::PURPOSE, Tag::EC_CURVE, Tag::DIGEST, Tag::NO_AUTH_REQUIRED] {
+        assert_eq!(
+            SecurityLevel::TRUSTED_ENVIRONMENT,
+            assert_tag_hardware_enforced(&keystore2, &sec_level, &gen_params, tag)
+        );
+    }
+}
+
+/// Use the given EC signing key for sample sign operations until keystore refuses to use it,
+/// but at most `max_attempts` times. Returns the number of successful uses together with the
+/// error which prevented further use, if any.
+fn use_key_until_exhausted(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    key_metadata: &KeyMetadata,
+    max_attempts: i32,
+) -> (i32, Option<Error>) {
+    let op_params =
+        authorizations::AuthSetBuilder::new().purpose(KeyPurpose::SIGN).digest(Digest::SHA_2_256);
+
+    for uses in 0..max_attempts {
+        let result = key_generations::map_ks_error(
+            sec_level.createOperation(&key_metadata.key, &op_params, false).and_then(|resp| {
+                let op = resp.iOperation.unwrap();
+                op.update(b"my message")?;
+                op.finish(None, None)
+            }),
+        );
+        if let Err(e) = result {
+            return (uses, Some(e));
+        }
+    }
+    (max_attempts, None)
+}
+
 /// Generate a key with `USAGE_COUNT_LIMIT`. Test should successfully generate
 /// a key and verify the key characteristics. Test should be able to use the key successfully
 /// `MAX_USES_COUNT` times. After exceeding key usage `MAX_USES_COUNT` times
@@ -662,6 +803,48 @@ fn keystore2_gen_key_auth_include_unique_id_success() {
     delete_app_key(&keystore2, alias_second).unwrap();
 }
 
+/// Generate an attested key with `Tag::INCLUDE_UNIQUE_ID` set and read `RESET_SINCE_ID_ROTATION`
+/// from its attest record. Keystore adds the tag only if a factory reset happened since the last
+/// unique ID rotation, so the test can't expect a particular value. It checks that the tag is
+/// attested whenever the key characteristics contain it. Test is skipped if the key is not
+/// attested.
+#[test]
+fn keystore2_gen_key_auth_reset_since_id_rotation() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+    let alias = test_alias("keystore2_gen_key_auth_reset_since_id_rotation");
+
+    let gen_params = authorizations::AuthSetBuilder::new()
+        .no_auth_required()
+        .algorithm(Algorithm::EC)
+        .purpose(KeyPurpose::SIGN)
+        .purpose(KeyPurpose::VERIFY)
+        .digest(Digest::SHA_2_256)
+        .ec_curve(EcCurve::P_256)
+        .attestation_challenge(b"foo".to_vec())
+        .include_unique_id();
+    let key_metadata = key_generations::generate_key(&sec_level, &gen_params, &alias).unwrap();
+
+    let cert = key_metadata.certificate.as_ref().unwrap();
+    let reset_since_rotation = extract_reset_since_rotation(cert);
+    delete_app_key(&keystore2, &alias).unwrap();
+
+    let reset_since_rotation = match reset_since_rotation {
+        Some(reset_since_rotation) => reset_since_rotation,
+        None => {
+            log_test_skip("the key is not attested");
+            return;
+        }
+    };
+    let in_characteristics =
+        key_generations::get_key_auth(&key_metadata.authorizations, Tag::RESET_SINCE_ID_ROTATION)
+            .is_some();
+    assert!(
+        reset_since_rotation || !in_characteristics,
+        "RESET_SINCE_ID_ROTATION is in the key characteristics but not in the attest record."
+    );
+}
+
 /// Generate a key with `APPLICATION_DATA`. Test should create an operation using the
 /// same `APPLICATION_DATA` successfully.
 #[test]
@@ -983,3 +1166,426 @@ fn keystore2_gen_key_auth_serial_number_subject_test_success() {
     verify_certificate_serial_num(key_metadata.certificate.as_ref().unwrap(), &serial);
     delete_app_key(&keystore2, alias).unwrap();
 }
+
+/// Generate a key with `CERTIFICATE_SUBJECT` holding a multi-component DN and a
+/// `CERTIFICATE_SERIAL`. Test should generate a key whose certificate has exactly the requested
+/// subject and serial number.
+#[test]
+fn keystore2_gen_key_auth_cert_subject_serial_roundtrip() {
+    skip_tests_if_keymaster_impl_present!();
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    let mut x509_name = X509NameBuilder::new().unwrap();
+    x509_name.append_entry_by_text("C", "US").unwrap();
+    x509_name.append_entry_by_text("O", "test cert organization").unwrap();
+    x509_name.append_entry_by_text("CN", "test cert subject").unwrap();
+    let x509_name = x509_name.build().to_der().unwrap();
+
+    let serial = BigNum::from_u32(0x5e71a1).unwrap();
+
+    let gen_params = authorizations::AuthSetBuilder::new()
+        .no_auth_required()
+        .algorithm(Algorithm::EC)
+        .purpose(KeyPurpose::SIGN)
+        .purpose(KeyPurpose::VERIFY)
+        .digest(Digest::SHA_2_256)
+        .ec_curve(EcCurve::P_256)
+        .cert_subject_name(x509_name.clone())
+        .cert_serial(serial.to_vec());
+
+    let alias = test_alias("keystore2_gen_key_auth_cert_subject_serial_roundtrip");
+    let key_metadata = key_generations::generate_key(&sec_level, &gen_params, &alias).unwrap();
+    assert_cert_subject_matches(&key_metadata, &x509_name, &serial);
+    delete_app_key(&keystore2, &alias).unwrap();
+}
+
+/// Generate a key with `CERTIFICATE_NOT_BEFORE` and `CERTIFICATE_NOT_AFTER` tags. Test should
+/// generate a key whose certificate has the requested validity period.
+#[test]
+fn keystore2_gen_key_auth_cert_validity_test_success() {
+    skip_tests_if_keymaster_impl_present!();
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    let not_before = 1_600_000_000_000;
+    let not_after = 2_000_000_000_000;
+    let gen_params = authorizations::AuthSetBuilder::new()
+        .no_auth_required()
+        .algorithm(Algorithm::EC)
+        .purpose(KeyPurpose::SIGN)
+        .purpose(KeyPurpose::VERIFY)
+        .digest(Digest::SHA_2_256)
+        .ec_curve(EcCurve::P_256)
+        .cert_not_before(not_before)
+        .cert_not_after(not_after);
+
+    let alias = test_alias("keystore2_gen_key_auth_cert_validity_test_success");
+    let key_metadata = key_generations::generate_key(&sec_level, &gen_params, &alias).unwrap();
+    verify_certificate_validity(key_metadata.certificate.as_ref().unwrap(), not_before, not_after);
+    delete_app_key(&keystore2, &alias).unwrap();
+}
+
+/// Try to generate a key with a `CERTIFICATE_SUBJECT` which isn't a DER encoded X.509 name. Test
+/// should fail to generate a key with an error code `INVALID_ARGUMENT`.
+#[test]
+fn keystore2_gen_key_auth_invalid_cert_subject_fail() {
+    skip_tests_if_keymaster_impl_present!();
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    let gen_params = authorizations::AuthSetBuilder::new()
+        .no_auth_required()
+        .algorithm(Algorithm::EC)
+        .purpose(KeyPurpose::SIGN)
+        .purpose(KeyPurpose::VERIFY)
+        .digest(Digest::SHA_2_256)
+        .ec_curve(EcCurve::P_256)
+        .cert_subject_name(b"not a DER encoded name".to_vec());
+
+    let alias = test_alias("keystore2_gen_key_auth_invalid_cert_subject_fail");
+    let result = key_generations::map_ks_error(key_generations::generate_key(
+        &sec_level,
+        &gen_params,
+        &alias,
+    ));
+    assert_eq!(Err(Error::Km(ErrorCode::INVALID_ARGUMENT)), result.map(|_| ()));
+}
+
+/// Request a timestamp token from the secure clock. Test should receive a MACed token for the
+/// given challenge, or skip if the device does not provide a secure clock.
+#[test]
+fn keystore2_get_timestamp_token_success() {
+    let challenge = 0x5ec0_c10c;
+    let token = match get_timestamp_token(challenge) {
+        Err(Error::SecureClockUnsupported) => return,
+        result => result.unwrap(),
+    };
+
+    assert_eq!(challenge, token.challenge);
+    assert!(token.timestamp.milliSeconds >= 0);
+    assert!(!token.mac.is_empty());
+}
+
+/// Try to generate AES, 3DES, HMAC, RSA and EC keys with out-of-range key sizes. Test should fail
+/// to generate every key with `UNSUPPORTED_KEY_SIZE` error code.
+#[test]
+fn keystore2_gen_key_fails_invalid_key_sizes() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    for algorithm in
+        [Algorithm::AES, Algorithm::TRIPLE_DES, Algorithm::HMAC, Algorithm::RSA, Algorithm::EC]
+    {
+        let deviations = assert_invalid_key_size_rejected(&keystore2, &sec_level, algorithm);
+        assert!(deviations.is_empty(), "{:?}: unexpected outcomes {:?}", algorithm, deviations);
+    }
+}
+
+/// Steps of the auth-bound signing round trip, used to report which step failed.
+#[derive(Debug, PartialEq)]
+enum AuthBoundSignStep {
+    GenerateKey,
+    CreateOperation,
+    ReadChallenge,
+    GetAuthToken,
+    AddAuthToken,
+    Finish,
+}
+
+/// Generate an EC signing key which requires a password auth token, bound to the operation
+/// challenge, for every operation.
+fn generate_per_op_auth_key(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    alias: &str,
+    sid: i64,
+) -> Result<KeyMetadata, Error> {
+    let gen_params = authorizations::AuthSetBuilder::new()
+        .user_secure_id(sid)
+        .user_auth_type(HardwareAuthenticatorType::PASSWORD)
+        .algorithm(Algorithm::EC)
+        .purpose(KeyPurpose::SIGN)
+        .purpose(KeyPurpose::VERIFY)
+        .digest(Digest::SHA_2_256)
+        .ec_curve(EcCurve::P_256);
+    key_generations::map_ks_error(key_generations::generate_key(sec_level, &gen_params, alias))
+}
+
+/// Build a password auth token for the given operation challenge and secure user id. The token
+/// isn't issued by an authenticator, so it doesn't carry a valid MAC.
+fn unmaced_auth_token(challenge: i64, sid: i64) -> HardwareAuthToken {
+    HardwareAuthToken {
+        challenge,
+        userId: sid,
+        authenticatorId: sid,
+        authenticatorType: HardwareAuthenticatorType::PASSWORD,
+        timestamp: Timestamp { milliSeconds: 500 },
+        mac: vec![],
+    }
+}
+
+/// Get the Gatekeeper HAL, if the device provides an AIDL implementation.
+fn get_gatekeeper() -> Option<binder::Strong<dyn IGatekeeper>> {
+    binder::get_interface(GATEKEEPER_SERVICE_NAME).ok()
+}
+
+/// Enroll a password for the given Gatekeeper user. Returns the secure user id along with the
+/// password handle needed to verify the password.
+fn gatekeeper_enroll(
+    gatekeeper: &binder::Strong<dyn IGatekeeper>,
+    user_id: i32,
+    password: &[u8],
+) -> Result<(i64, Vec<u8>), Error> {
+    let response = key_generations::map_ks_error(gatekeeper.enroll(user_id, &[], &[], password))?;
+    assert_eq!(STATUS_OK, response.statusCode, "Gatekeeper enrollment failed.");
+    Ok((response.secureUserId, response.data))
+}
+
+/// Verify the given password with Gatekeeper, which issues a MACed auth token for `challenge`.
+fn gatekeeper_auth_token(
+    gatekeeper: &binder::Strong<dyn IGatekeeper>,
+    user_id: i32,
+    password_handle: &[u8],
+    password: &[u8],
+    challenge: i64,
+) -> Result<HardwareAuthToken, Error> {
+    let response = key_generations::map_ks_error(gatekeeper.verify(
+        user_id,
+        challenge,
+        password_handle,
+        password,
+    ))?;
+    assert_eq!(STATUS_OK, response.statusCode, "Gatekeeper verification failed.");
+    Ok(response.hardwareAuthToken)
+}
+
+/// Generate a per-operation auth-bound key with the secure user id `sid`, create a signing
+/// operation with it, add the auth token `get_token` returns for the operation's challenge and
+/// finish the operation. The signature is verified with the key's certificate. Returns the step
+/// which failed along with its error. The key is deleted in any case.
+fn auth_bound_sign_roundtrip(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    sid: i64,
+    get_token: impl FnOnce(i64) -> Result<HardwareAuthToken, Error>,
+) -> Result<(), (AuthBoundSignStep, Error)> {
+    let keystore2 = get_keystore_service();
+    let alias = test_alias("auth_bound_sign_roundtrip");
+
+    let key_metadata = generate_per_op_auth_key(sec_level, &alias, sid)
+        .map_err(|e| (AuthBoundSignStep::GenerateKey, e))?;
+    let result = (|| {
+        let op_response = sec_level
+            .createOperation(
+                &key_metadata.key,
+                &authorizations::AuthSetBuilder::new()
+                    .purpose(KeyPurpose::SIGN)
+                    .digest(Digest::SHA_2_256),
+                false,
+            )
+            .map_err(|e| (AuthBoundSignStep::CreateOperation, key_generations::map_ks_error(e)))?;
+        let challenge = extract_operation_challenge(&op_response)
+            .ok_or((AuthBoundSignStep::ReadChallenge, Error::Rc(ResponseCode::SYSTEM_ERROR)))?;
+        let token = get_token(challenge).map_err(|e| (AuthBoundSignStep::GetAuthToken, e))?;
+        get_keystore_auth_service()
+            .addAuthToken(&token)
+            .map_err(|e| (AuthBoundSignStep::AddAuthToken, key_generations::map_ks_error(e)))?;
+        let op = op_response
+            .iOperation
+            .ok_or((AuthBoundSignStep::CreateOperation, Error::Rc(ResponseCode::SYSTEM_ERROR)))?;
+        let signature = op
+            .update(b"my message")
+            .and_then(|_| op.finish(None, None))
+            .map_err(|e| (AuthBoundSignStep::Finish, key_generations::map_ks_error(e)))?
+            .expect("Finish should have returned a signature.");
+
+        let cert = X509::from_der(key_metadata.certificate.as_ref().unwrap()).unwrap();
+        let pub_key = cert.public_key().unwrap();
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &pub_key).unwrap();
+        verifier.update(b"my message").unwrap();
+        assert!(verifier.verify(&signature).unwrap(), "Signature verification failed.");
+        Ok(())
+    })();
+    delete_app_key(&keystore2, &alias).unwrap();
+    result
+}
+
+/// Generate an EC key requiring per-operation password authentication and create an operation
+/// with it. Test should receive an operation challenge for the auth-bound key, and no challenge
+/// for an operation of a key which doesn't require authentication.
+#[test]
+fn keystore2_per_op_auth_key_operation_challenge() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+    let op_params =
+        authorizations::AuthSetBuilder::new().purpose(KeyPurpose::SIGN).digest(Digest::SHA_2_256);
+
+    let alias = test_alias("keystore2_per_op_auth_key_operation_challenge");
+    let key_metadata = generate_per_op_auth_key(&sec_level, &alias, 0x5ec0_1d).unwrap();
+    let op_response = sec_level.createOperation(&key_metadata.key, &op_params, false).unwrap();
+    assert!(extract_operation_challenge(&op_response).is_some());
+    delete_app_key(&keystore2, &alias).unwrap();
+
+    let no_auth_alias = test_alias("keystore2_per_op_auth_key_operation_challenge_no_auth_key");
+    let key_metadata = key_generations::generate_ec_p256_signing_key(
+        &sec_level,
+        Domain::APP,
+        -1,
+        Some(no_auth_alias.clone()),
+        None,
+    )
+    .unwrap();
+    let op_response = sec_level.createOperation(&key_metadata.key, &op_params, false).unwrap();
+    assert_eq!(None, extract_operation_challenge(&op_response));
+    delete_app_key(&keystore2, &no_auth_alias).unwrap();
+}
+
+/// Run the auth-bound signing round trip with a password auth token issued by Gatekeeper for the
+/// operation challenge. Test should sign successfully and the signature should verify with the
+/// key's certificate. Test is skipped if the device has no AIDL Gatekeeper HAL.
+#[test]
+fn keystore2_auth_bound_sign_roundtrip() {
+    const GK_TEST_USER_ID: i32 = 0x5ec0_1d;
+    let password = b"ks_auth_bound_sign_roundtrip_password";
+
+    let gatekeeper = match get_gatekeeper() {
+        Some(gatekeeper) => gatekeeper,
+        None => {
+            log_test_skip("the device has no AIDL Gatekeeper HAL");
+            return;
+        }
+    };
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    let (sid, password_handle) = gatekeeper_enroll(&gatekeeper, GK_TEST_USER_ID, password).unwrap();
+    let result = auth_bound_sign_roundtrip(&sec_level, sid, |challenge| {
+        gatekeeper_auth_token(&gatekeeper, GK_TEST_USER_ID, &password_handle, password, challenge)
+    });
+    // Not every Gatekeeper implementation supports deleting users.
+    let _ = gatekeeper.deleteUser(GK_TEST_USER_ID);
+    assert_eq!(Ok(()), result);
+}
+
+/// Run the auth-bound signing round trip with an auth token which isn't MACed. All steps up to
+/// adding the auth token for the operation challenge should succeed, but finishing the operation
+/// is expected to fail with `KEY_USER_NOT_AUTHENTICATED` since KeyMint can't verify the token.
+#[test]
+fn keystore2_auth_bound_sign_unmaced_token_fails() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+    let sid = 0x5ec0_1d;
+
+    let result = auth_bound_sign_roundtrip(&sec_level, sid, |challenge| {
+        Ok(unmaced_auth_token(challenge, sid))
+    });
+    assert_eq!(
+        Err((AuthBoundSignStep::Finish, Error::Km(ErrorCode::KEY_USER_NOT_AUTHENTICATED))),
+        result
+    );
+}
+
+/// Generate an EC key using the given StrongBox security level and verify that the key is
+/// enforced by StrongBox rather than silently generated in another backend. Returns the actual
+/// security level of the key.
+fn assert_no_silent_strongbox_fallback(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+) -> SecurityLevel {
+    let keystore2 = get_keystore_service();
+    let alias = test_alias("assert_no_silent_strongbox_fallback");
+    let key_metadata = key_generations::generate_ec_p256_signing_key(
+        sec_level,
+        Domain::APP,
+        -1,
+        Some(alias.clone()),
+        None,
+    )
+    .unwrap();
+    delete_app_key(&keystore2, &alias).unwrap();
+
+    let actual_level = key_generations::extract_security_level(&key_metadata.authorizations)
+        .expect("Key authorizations don't report the algorithm.");
+    assert_eq!(
+        SecurityLevel::STRONGBOX,
+        actual_level,
+        "StrongBox key generation fell back to {:?}.",
+        actual_level
+    );
+    actual_level
+}
+
+/// Generate a key using the `STRONGBOX` security level, if available, and verify that the key's
+/// characteristics are enforced by StrongBox.
+#[test]
+fn keystore2_strongbox_key_gen_no_silent_fallback() {
+    let keystore2 = get_keystore_service();
+    let result =
+        key_generations::map_ks_error(keystore2.getSecurityLevel(SecurityLevel::STRONGBOX));
+    if result.is_err() {
+        assert_eq!(Error::Km(ErrorCode::HARDWARE_TYPE_UNAVAILABLE), result.unwrap_err());
+        return;
+    }
+    let sec_level = result.unwrap();
+
+    assert_eq!(SecurityLevel::STRONGBOX, assert_no_silent_strongbox_fallback(&sec_level));
+}
+
+/// Try to generate a key with both `NO_AUTH_REQUIRED` and `USER_SECURE_ID` tags and use it. The
+/// backend should reject the contradictory combination at generation time with
+/// `INVALID_ARGUMENT`. KeyMint doesn't require backends to validate this combination though, so a
+/// backend may generate the key. Keystore's `Enforcements::authorize_create` then refuses to
+/// create operations with such a key with `INVALID_KEY_BLOB`, which is accepted as well. Returns
+/// whether the key was accepted at generation time along with the rejecting error.
+fn assert_conflicting_auth_tags_rejected(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+) -> (bool, Error) {
+    let keystore2 = get_keystore_service();
+    let alias = test_alias("assert_conflicting_auth_tags_rejected");
+    let gen_params = authorizations::AuthSetBuilder::new()
+        .no_auth_required()
+        .user_secure_id(0x5ec0_1d)
+        .user_auth_type(HardwareAuthenticatorType::PASSWORD)
+        .algorithm(Algorithm::EC)
+        .purpose(KeyPurpose::SIGN)
+        .digest(Digest::SHA_2_256)
+        .ec_curve(EcCurve::P_256);
+
+    let key_metadata = match key_generations::map_ks_error(key_generations::generate_key(
+        sec_level,
+        &gen_params,
+        &alias,
+    )) {
+        Ok(key_metadata) => key_metadata,
+        Err(e) => {
+            assert_eq!(Error::Km(ErrorCode::INVALID_ARGUMENT), e);
+            return (false, e);
+        }
+    };
+
+    let result = key_generations::map_ks_error(sec_level.createOperation(
+        &key_metadata.key,
+        &authorizations::AuthSetBuilder::new().purpose(KeyPurpose::SIGN).digest(Digest::SHA_2_256),
+        false,
+    ));
+    delete_app_key(&keystore2, &alias).unwrap();
+    match result {
+        Ok(_) => panic!("Key with both NO_AUTH_REQUIRED and USER_SECURE_ID was usable."),
+        Err(e) => {
+            assert_eq!(Error::Km(ErrorCode::INVALID_KEY_BLOB), e);
+            (true, e)
+        }
+    }
+}
+
+/// Generate a key with both `NO_AUTH_REQUIRED` and `USER_SECURE_ID` tags. Test should fail to
+/// generate the key with `INVALID_ARGUMENT`, or if the backend generates it, fail to create an
+/// operation with it with `INVALID_KEY_BLOB`.
+#[test]
+fn keystore2_gen_key_conflicting_auth_tags_rejected() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    match assert_conflicting_auth_tags_rejected(&sec_level) {
+        (false, e) => assert_eq!(Error::Km(ErrorCode::INVALID_ARGUMENT), e),
+        (true, e) => assert_eq!(Error::Km(ErrorCode::INVALID_KEY_BLOB), e),
+    }
+}
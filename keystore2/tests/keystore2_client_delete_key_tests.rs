@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use nix::unistd::getuid;
+use std::thread;
 
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     ErrorCode::ErrorCode, SecurityLevel::SecurityLevel,
@@ -23,6 +23,8 @@ use android_system_keystore2::aidl::android::system::keystore2::{
 
 use keystore2_test_utils::{get_keystore_service, key_generations, key_generations::Error};
 
+use crate::keystore2_client_test_utils::test_alias;
+
 /// Generate a key and delete it using keystore2 service `deleteKey` API. Test should successfully
 /// delete the generated key.
 #[test]
@@ -133,7 +135,7 @@ fn keystore2_delete_key_fails_with_missing_key_blob() {
 fn keystore2_delete_key_blob_fail() {
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
-    let alias = format!("ks_delete_keyblob_test_key_{}", getuid());
+    let alias = test_alias("keystore2_delete_key_blob_fail");
 
     let key_metadata = key_generations::generate_ec_p256_signing_key(
         &sec_level,
@@ -148,3 +150,87 @@ fn keystore2_delete_key_blob_fail() {
     assert!(result.is_err());
     assert_eq!(Error::Km(ErrorCode::INVALID_ARGUMENT), result.unwrap_err());
 }
+
+/// Generate and delete a key with the given alias in a loop from two threads at the same time.
+/// Every generation must succeed and every deletion must either succeed or fail with
+/// `KEY_NOT_FOUND` because the other thread deleted the key first. Afterwards the alias must be in
+/// a consistent state, i.e. listed by `listEntries` if and only if its key entry can be loaded.
+/// Returns whether a key with the alias exists at the end.
+fn assert_gen_delete_race_safe(alias: &str, iterations: usize) -> bool {
+    let handles: Vec<_> = (0..2)
+        .map(|_| {
+            let alias = alias.to_string();
+            thread::spawn(move || {
+                let keystore2 = get_keystore_service();
+                let sec_level =
+                    keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+                let key = KeyDescriptor {
+                    domain: Domain::APP,
+                    nspace: -1,
+                    alias: Some(alias.clone()),
+                    blob: None,
+                };
+                for _ in 0..iterations {
+                    key_generations::generate_ec_p256_signing_key(
+                        &sec_level,
+                        Domain::APP,
+                        -1,
+                        Some(alias.clone()),
+                        None,
+                    )
+                    .unwrap();
+                    match key_generations::map_ks_error(keystore2.deleteKey(&key)) {
+                        Ok(()) | Err(Error::Rc(ResponseCode::KEY_NOT_FOUND)) => {}
+                        Err(e) => panic!("Unexpected error while deleting a key: {:?}", e),
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("Generate/delete thread panicked.");
+    }
+
+    let keystore2 = get_keystore_service();
+    let key = KeyDescriptor {
+        domain: Domain::APP,
+        nspace: -1,
+        alias: Some(alias.to_string()),
+        blob: None,
+    };
+    let listed = keystore2
+        .listEntries(Domain::APP, -1)
+        .unwrap()
+        .iter()
+        .any(|entry| entry.alias.as_deref() == Some(alias));
+    let exists = match key_generations::map_ks_error(keystore2.getKeyEntry(&key)) {
+        Ok(_) => true,
+        Err(Error::Rc(ResponseCode::KEY_NOT_FOUND)) => false,
+        Err(e) => panic!("Unexpected error while loading a key: {:?}", e),
+    };
+    assert_eq!(
+        listed, exists,
+        "Inconsistent state for {}: listed {}, exists {}",
+        alias, listed, exists
+    );
+    exists
+}
+
+/// Generate and delete keys with the same alias from two threads concurrently. Test should see
+/// no unexpected errors and find the alias in a consistent state afterwards.
+#[test]
+fn keystore2_concurrent_gen_delete_same_alias() {
+    let alias = test_alias("keystore2_concurrent_gen_delete_same_alias");
+
+    if assert_gen_delete_race_safe(&alias, 50) {
+        let keystore2 = get_keystore_service();
+        keystore2
+            .deleteKey(&KeyDescriptor {
+                domain: Domain::APP,
+                nspace: -1,
+                alias: Some(alias),
+                blob: None,
+            })
+            .unwrap();
+    }
+}
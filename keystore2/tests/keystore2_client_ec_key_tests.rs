@@ -12,12 +12,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use nix::unistd::{getuid, Gid, Uid};
+use nix::unistd::{Gid, Uid};
 use rustutils::users::AID_USER_OFFSET;
 
+use openssl::hash::MessageDigest;
+use openssl::pkey::{Id, PKey};
+use openssl::sign::Verifier;
+use openssl::x509::X509;
+
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     Algorithm::Algorithm, Digest::Digest, EcCurve::EcCurve, ErrorCode::ErrorCode,
-    KeyPurpose::KeyPurpose, SecurityLevel::SecurityLevel,
+    KeyParameterValue::KeyParameterValue, KeyPurpose::KeyPurpose, SecurityLevel::SecurityLevel,
 };
 use android_system_keystore2::aidl::android::system::keystore2::{
     CreateOperationResponse::CreateOperationResponse, Domain::Domain,
@@ -30,9 +35,11 @@ use keystore2_test_utils::{
 };
 
 use crate::keystore2_client_test_utils::{
-    delete_app_key, execute_op_run_as_child, get_vsr_api_level, perform_sample_sign_operation,
-    BarrierReached, ForcedOp, TestOutcome,
+    delete_app_key, execute_op_run_as_child, get_keymint_version, get_supported_curves,
+    get_supported_digests, get_vsr_api_level, perform_sample_asym_sign_verify_op,
+    perform_sample_sign_operation, test_alias, BarrierReached, ForcedOp, TestOutcome,
 };
+use crate::skip_if_unsupported;
 
 macro_rules! test_ec_sign_key_op_success {
     ( $test_name:ident, $digest:expr, $ec_curve:expr ) => {
@@ -226,7 +233,7 @@ fn keystore2_get_key_entry_blob_fail() {
 fn keystore2_generate_key_invalid_domain() {
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
-    let alias = format!("ks_invalid_test_key_{}", getuid());
+    let alias = test_alias("keystore2_generate_key_invalid_domain");
 
     let result = key_generations::map_ks_error(key_generations::generate_ec_key(
         &sec_level,
@@ -246,7 +253,7 @@ fn keystore2_generate_key_invalid_domain() {
 fn keystore2_generate_ec_key_missing_curve() {
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
-    let alias = format!("ks_ec_no_curve_test_key_{}", getuid());
+    let alias = test_alias("keystore2_generate_ec_key_missing_curve");
 
     // Don't provide EC curve.
     let gen_params = authorizations::AuthSetBuilder::new()
@@ -276,13 +283,198 @@ fn keystore2_generate_ec_key_missing_curve() {
     ));
 }
 
+/// Generate EC keys with each of the curves defined by KeyMint. Test should be able to generate
+/// keys with all curves supported by the backend, `CURVE_25519` is skipped if the backend doesn't
+/// support it. Generating a key with a curve unknown to KeyMint should fail with
+/// `UNSUPPORTED_EC_CURVE`.
+#[test]
+fn keystore2_generate_ec_key_sweep_curves() {
+    let keystore2 = get_keystore_service();
+    let security_level = SecurityLevel::TRUSTED_ENVIRONMENT;
+    let sec_level = keystore2.getSecurityLevel(security_level).unwrap();
+    let (_, features) = get_keymint_version(security_level);
+
+    for curve in key_generations::EC_CURVES {
+        if *curve == EcCurve::CURVE_25519 && !features.curve_25519 {
+            continue;
+        }
+        let digest = if *curve == EcCurve::CURVE_25519 { Digest::NONE } else { Digest::SHA_2_256 };
+        let alias = test_alias(&format!("keystore2_generate_ec_key_sweep_curves_{}", curve.0));
+        key_generations::generate_ec_key(
+            &sec_level,
+            Domain::APP,
+            -1,
+            Some(alias.clone()),
+            *curve,
+            digest,
+        )
+        .unwrap_or_else(|e| panic!("Failed to generate EC key with curve {curve:?}: {e:?}"));
+        delete_app_key(&keystore2, &alias).unwrap();
+    }
+
+    let alias = test_alias("keystore2_generate_ec_key_sweep_curves_invalid");
+    let result = key_generations::map_ks_error(key_generations::generate_ec_key(
+        &sec_level,
+        Domain::APP,
+        -1,
+        Some(alias),
+        EcCurve(-1),
+        Digest::SHA_2_256,
+    ));
+    assert!(result.is_err());
+    assert_eq!(Error::Km(ErrorCode::UNSUPPORTED_EC_CURVE), result.unwrap_err());
+}
+
+/// Outcome of signing with each (curve, digest) combination, see `assert_curve_digest_matrix`.
+#[derive(Debug)]
+pub struct CurveDigestMatrix(pub Vec<(EcCurve, Digest, Result<(), Error>)>);
+
+impl std::fmt::Display for CurveDigestMatrix {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{:<12} {:<10} result", "curve", "digest")?;
+        for (curve, digest, result) in &self.0 {
+            let result = match result {
+                Ok(()) => "Ok".to_string(),
+                Err(e) => format!("{:?}", e),
+            };
+            writeln!(
+                f,
+                "{:<12} {:<10} {}",
+                format!("{:?}", curve),
+                format!("{:?}", digest),
+                result
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Generate EC signing keys for each curve supported by the backend together with each digest
+/// and try to sign a sample message with them. Records which (curve, digest) combinations are
+/// accepted. Every NIST curve must accept `SHA_2_256` and `CURVE_25519` must accept `NONE`.
+fn assert_curve_digest_matrix(security_level: SecurityLevel) -> CurveDigestMatrix {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(security_level).unwrap();
+    let (_, features) = get_keymint_version(security_level);
+    let digests = [
+        Digest::NONE,
+        Digest::MD5,
+        Digest::SHA1,
+        Digest::SHA_2_224,
+        Digest::SHA_2_256,
+        Digest::SHA_2_384,
+        Digest::SHA_2_512,
+    ];
+
+    let mut matrix = Vec::new();
+    for curve in key_generations::EC_CURVES {
+        if *curve == EcCurve::CURVE_25519 && !features.curve_25519 {
+            continue;
+        }
+        for digest in digests {
+            let alias = test_alias(&format!("assert_curve_digest_matrix_{}_{}", curve.0, digest.0));
+            let result = key_generations::map_ks_error(create_ec_key_and_operation(
+                &sec_level,
+                Domain::APP,
+                -1,
+                Some(alias.clone()),
+                digest,
+                *curve,
+            ))
+            .and_then(|op_response| {
+                key_generations::map_ks_error(perform_sample_sign_operation(
+                    &op_response.iOperation.unwrap(),
+                ))
+            });
+            // Key generation itself may have been rejected, in which case there is nothing to
+            // delete.
+            let _ = delete_app_key(&keystore2, &alias);
+            matrix.push((*curve, digest, result));
+        }
+    }
+
+    for (curve, digest, result) in &matrix {
+        let required = if *curve == EcCurve::CURVE_25519 {
+            *digest == Digest::NONE
+        } else {
+            *digest == Digest::SHA_2_256
+        };
+        if required {
+            assert_eq!(&Ok(()), result, "Signing failed for curve {curve:?}, digest {digest:?}");
+        }
+    }
+    CurveDigestMatrix(matrix)
+}
+
+/// Sign with each combination of EC curve and digest and print the resulting matrix of accepted
+/// combinations.
+#[test]
+fn keystore2_ec_curve_digest_matrix() {
+    let matrix = assert_curve_digest_matrix(SecurityLevel::TRUSTED_ENVIRONMENT);
+    println!("{}", matrix);
+}
+
+/// Generate an EC P-256 signing key and sign the same message twice. ECDSA uses a random nonce
+/// per signature, so the two signatures are expected to differ. Identical signatures are only
+/// tolerated if `allow_deterministic` is set, which is meant for implementations known to use
+/// deterministic nonces. Returns whether the signatures differed.
+fn assert_ecdsa_signatures_differ(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    allow_deterministic: bool,
+) -> bool {
+    let alias = test_alias("assert_ecdsa_signatures_differ");
+    let key_metadata = key_generations::generate_ec_key(
+        sec_level,
+        Domain::APP,
+        -1,
+        Some(alias.clone()),
+        EcCurve::P_256,
+        Digest::SHA_2_256,
+    )
+    .unwrap();
+
+    let sign = || -> Vec<u8> {
+        let op = sec_level
+            .createOperation(
+                &key_metadata.key,
+                &authorizations::AuthSetBuilder::new()
+                    .purpose(KeyPurpose::SIGN)
+                    .digest(Digest::SHA_2_256),
+                false,
+            )
+            .unwrap()
+            .iOperation
+            .unwrap();
+        op.update(b"my message").unwrap();
+        op.finish(None, None).unwrap().expect("Signature is missing.")
+    };
+    let differ = sign() != sign();
+    assert!(
+        differ || allow_deterministic,
+        "Signing the same message twice produced identical ECDSA signatures."
+    );
+
+    delete_app_key(&get_keystore_service(), &alias).unwrap();
+    differ
+}
+
+/// Sign the same message twice using an EC key. The signatures should differ because ECDSA uses
+/// a randomized nonce.
+#[test]
+fn keystore2_ec_signatures_differ() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    assert!(assert_ecdsa_signatures_differ(&sec_level, false));
+}
+
 /// Try to generate a EC key with curve `CURVE_25519` having `SIGN and AGREE_KEY` purposes.
 /// `INCOMPATIBLE_PURPOSE` error response is expected.
 #[test]
 fn keystore2_generate_ec_key_25519_multi_purpose() {
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
-    let alias = format!("ks_ec_no_curve_test_key_{}", getuid());
+    let alias = test_alias("keystore2_generate_ec_key_25519_multi_purpose");
 
     // Specify `SIGN and AGREE_KEY` purposes.
     let gen_params = authorizations::AuthSetBuilder::new()
@@ -317,7 +509,7 @@ fn keystore2_ec_25519_generate_key_success() {
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
 
-    let alias = format!("ks_ec_25519_none_test_key_gen_{}", getuid());
+    let alias = test_alias("keystore2_ec_25519_generate_key_success");
     let key_metadata = key_generations::generate_ec_key(
         &sec_level,
         Domain::APP,
@@ -363,7 +555,7 @@ fn keystore2_ec_25519_generate_key_fail() {
     ];
 
     for digest in digests {
-        let alias = format!("ks_ec_25519_test_key_gen_{}{}", getuid(), digest.0);
+        let alias = test_alias(&format!("keystore2_ec_25519_generate_key_fail_{}", digest.0));
         let key_metadata = key_generations::generate_ec_key(
             &sec_level,
             Domain::APP,
@@ -524,3 +716,182 @@ fn keystore2_generate_key_with_blob_domain() {
     // Delete the generated key blob.
     sec_level.deleteKey(&key_metadata.key).unwrap();
 }
+
+/// Generate an EC P-256 key authorized for both `SIGN` and `VERIFY` purposes. Test should find
+/// both purposes in the key authorizations, create a signature with the key and verify that
+/// signature in-process using the public key from the key's certificate.
+#[test]
+fn keystore2_ec_sign_verify_purposes_key_roundtrip() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+    let alias = test_alias("keystore2_ec_sign_verify_purposes_key_roundtrip");
+
+    let key_metadata = key_generations::generate_ec_p256_signing_key(
+        &sec_level,
+        Domain::APP,
+        -1,
+        Some(alias.clone()),
+        None,
+    )
+    .unwrap();
+
+    for purpose in [KeyPurpose::SIGN, KeyPurpose::VERIFY] {
+        assert!(
+            key_metadata
+                .authorizations
+                .iter()
+                .any(|auth| auth.keyParameter.value == KeyParameterValue::KeyPurpose(purpose)),
+            "{:?} not found in key authorizations",
+            purpose
+        );
+    }
+
+    perform_sample_asym_sign_verify_op(&sec_level, &key_metadata, None, Some(Digest::SHA_2_256));
+
+    delete_app_key(&keystore2, &alias).unwrap();
+}
+
+/// Secret key of TEST 2 from RFC 8032 section 7.1.
+static RFC8032_TEST2_SECRET_KEY: &[u8] = &[
+    0x4c, 0xcd, 0x08, 0x9b, 0x28, 0xff, 0x96, 0xda, 0x9d, 0xb6, 0xc3, 0x46, 0xec, 0x11, 0x4e, 0x0f,
+    0x5b, 0x8a, 0x31, 0x9f, 0x35, 0xab, 0xa6, 0x24, 0xda, 0x8c, 0xf6, 0xed, 0x4f, 0xb8, 0xa6, 0xfb,
+];
+
+/// Message of TEST 2 from RFC 8032 section 7.1.
+static RFC8032_TEST2_MESSAGE: &[u8] = &[0x72];
+
+/// Signature of TEST 2 from RFC 8032 section 7.1.
+static RFC8032_TEST2_SIGNATURE: &[u8] = &[
+    0x92, 0xa0, 0x09, 0xa9, 0xf0, 0xd4, 0xca, 0xb8, 0x72, 0x0e, 0x82, 0x0b, 0x5f, 0x64, 0x25, 0x40,
+    0xa2, 0xb2, 0x7b, 0x54, 0x16, 0x50, 0x3f, 0x8f, 0xb3, 0x76, 0x22, 0x23, 0xeb, 0xdb, 0x69, 0xda,
+    0x08, 0x5a, 0xc1, 0xe4, 0x3e, 0x15, 0x99, 0x6e, 0x45, 0x8f, 0x36, 0x13, 0xd0, 0xf1, 0x1d, 0x8c,
+    0x38, 0x7b, 0x2e, 0xae, 0xb4, 0x30, 0x2a, 0xee, 0xb0, 0x0d, 0x29, 0x16, 0x12, 0xbb, 0x0c, 0x00,
+];
+
+/// Import the Ed25519 secret key of RFC 8032 TEST 2 and sign the test message with it. Since
+/// Ed25519 signatures are deterministic, the signature must equal the published one. Returns
+/// `None` if the backend doesn't support importing `CURVE_25519` keys.
+fn assert_ed25519_matches_rfc8032(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+) -> Option<Vec<u8>> {
+    let keystore2 = get_keystore_service();
+    let private_key =
+        PKey::private_key_from_raw_bytes(RFC8032_TEST2_SECRET_KEY, Id::ED25519).unwrap();
+
+    let alias = test_alias("assert_ed25519_matches_rfc8032");
+    let import_params = authorizations::AuthSetBuilder::new()
+        .no_auth_required()
+        .algorithm(Algorithm::EC)
+        .ec_curve(EcCurve::CURVE_25519)
+        .purpose(KeyPurpose::SIGN)
+        .digest(Digest::NONE);
+    let key_metadata = match key_generations::map_ks_error(sec_level.importKey(
+        &key_generations::make_key_descriptor(Domain::APP, -1, Some(alias.clone()), None),
+        None,
+        &import_params,
+        0,
+        &private_key.private_key_to_pkcs8().unwrap(),
+    )) {
+        Ok(key_metadata) => key_metadata,
+        Err(Error::Km(ErrorCode::UNSUPPORTED_EC_CURVE))
+        | Err(Error::Km(ErrorCode::UNSUPPORTED_KEY_FORMAT)) => return None,
+        Err(e) => panic!("Failed to import Ed25519 key: {:?}", e),
+    };
+
+    let op_response = sec_level
+        .createOperation(
+            &key_metadata.key,
+            &authorizations::AuthSetBuilder::new().purpose(KeyPurpose::SIGN).digest(Digest::NONE),
+            false,
+        )
+        .unwrap();
+    let op = op_response.iOperation.unwrap();
+    let signature = op.finish(Some(RFC8032_TEST2_MESSAGE), None).unwrap().unwrap();
+    delete_app_key(&keystore2, &alias).unwrap();
+
+    assert_eq!(
+        RFC8032_TEST2_SIGNATURE,
+        signature.as_slice(),
+        "Expected signature {:02x?}, got {:02x?}",
+        RFC8032_TEST2_SIGNATURE,
+        signature
+    );
+    Some(signature)
+}
+
+/// Import the RFC 8032 Ed25519 test key and sign the test message. Test should produce the
+/// published signature, or skip if the backend doesn't support `CURVE_25519`.
+#[test]
+fn keystore2_ed25519_sign_rfc8032_vector() {
+    skip_if_unsupported!(curve_25519);
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    assert_ed25519_matches_rfc8032(&sec_level);
+}
+
+/// Probe the digests and curves supported by the TEE backend. Test should find the digests and
+/// curves every KeyMint implementation must support, and return the same cached results when
+/// queried again.
+#[test]
+fn keystore2_ec_probe_supported_digests_and_curves() {
+    let digests = get_supported_digests(SecurityLevel::TRUSTED_ENVIRONMENT);
+    assert!(digests.contains(&Digest::NONE), "{:?}", digests);
+    assert!(digests.contains(&Digest::SHA_2_256), "{:?}", digests);
+    assert_eq!(digests, get_supported_digests(SecurityLevel::TRUSTED_ENVIRONMENT));
+
+    let curves = get_supported_curves(SecurityLevel::TRUSTED_ENVIRONMENT);
+    for curve in [EcCurve::P_224, EcCurve::P_256, EcCurve::P_384, EcCurve::P_521] {
+        assert!(curves.contains(&curve), "{:?}", curves);
+    }
+    let (_, features) = get_keymint_version(SecurityLevel::TRUSTED_ENVIRONMENT);
+    assert_eq!(features.curve_25519, curves.contains(&EcCurve::CURVE_25519));
+    assert_eq!(curves, get_supported_curves(SecurityLevel::TRUSTED_ENVIRONMENT));
+}
+
+/// Generate an EC P-256 signing key, create a signing operation and finish it without any
+/// `update` call. The resulting signature must verify over the empty message. Returns the
+/// signature, or the error with which the backend rejected signing the empty message.
+fn assert_sign_empty_message(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+) -> Result<Vec<u8>, Error> {
+    let keystore2 = get_keystore_service();
+    let alias = test_alias("assert_sign_empty_message");
+    let key_metadata = key_generations::generate_ec_p256_signing_key(
+        sec_level,
+        Domain::APP,
+        -1,
+        Some(alias.clone()),
+        None,
+    )
+    .unwrap();
+
+    let result = key_generations::map_ks_error(sec_level.createOperation(
+        &key_metadata.key,
+        &authorizations::AuthSetBuilder::new().purpose(KeyPurpose::SIGN).digest(Digest::SHA_2_256),
+        false,
+    ))
+    .and_then(|op_response| {
+        let op = op_response.iOperation.unwrap();
+        key_generations::map_ks_error(op.finish(None, None))
+    });
+    delete_app_key(&keystore2, &alias).unwrap();
+
+    let signature = result?.expect("Signing operation should return a signature.");
+    let cert = X509::from_der(key_metadata.certificate.as_ref().unwrap()).unwrap();
+    let pub_key = cert.public_key().unwrap();
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &pub_key).unwrap();
+    verifier.update(&[]).unwrap();
+    assert!(verifier.verify(&signature).unwrap(), "Signature over empty message doesn't verify.");
+    Ok(signature)
+}
+
+/// Generate an EC key and finish a signing operation without passing any input. Test should
+/// produce a signature over the empty message which verifies with OpenSSL.
+#[test]
+fn keystore2_ec_sign_empty_message_success() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    assert!(assert_sign_empty_message(&sec_level).is_ok());
+}
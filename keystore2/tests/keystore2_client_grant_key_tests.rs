@@ -29,7 +29,7 @@ use keystore2_test_utils::{
 };
 
 use crate::keystore2_client_test_utils::{
-    generate_ec_key_and_grant_to_users, perform_sample_sign_operation,
+    generate_ec_key_and_grant_to_users, perform_sample_sign_operation, test_alias,
 };
 
 /// Generate an EC signing key and grant it to the user with given access vector.
@@ -338,7 +338,7 @@ fn keystore2_grant_key_fails_with_permission_denied() {
             let keystore2 = get_keystore_service();
             let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
             let access_vector = KeyPermission::GET_INFO.0;
-            let alias = format!("ks_grant_perm_denied_key_{}", getuid());
+            let alias = test_alias("keystore2_grant_key_fails_with_permission_denied");
             let mut grant_keys = generate_ec_key_and_grant_to_users(
                 &keystore2,
                 &sec_level,
@@ -414,7 +414,7 @@ fn keystore2_grant_key_fails_with_grant_perm_expect_perm_denied() {
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
     let access_vector = KeyPermission::GRANT.0;
-    let alias = format!("ks_grant_access_vec_key_{}", getuid());
+    let alias = test_alias("keystore2_grant_key_fails_with_grant_perm_expect_perm_denied");
     let user_id = 98;
     let application_id = 10001;
     let grantee_uid = user_id * AID_USER_OFFSET + application_id;
@@ -435,7 +435,7 @@ fn keystore2_grant_key_fails_with_grant_perm_expect_perm_denied() {
 #[test]
 fn keystore2_grant_fails_with_non_existing_key_expect_key_not_found_err() {
     let keystore2 = get_keystore_service();
-    let alias = format!("ks_grant_test_non_existing_key_5_{}", getuid());
+    let alias = test_alias("keystore2_grant_fails_with_non_existing_key_expect_key_not_found_err");
     let user_id = 98;
     let application_id = 10001;
     let grantee_uid = user_id * AID_USER_OFFSET + application_id;
@@ -472,7 +472,7 @@ fn keystore2_ungrant_key_success() {
         run_as::run_as(GRANTOR_SU_CTX, Uid::from_raw(0), Gid::from_raw(0), || {
             let keystore2 = get_keystore_service();
             let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
-            let alias = format!("ks_ungrant_test_key_1{}", getuid());
+            let alias = test_alias("keystore2_ungrant_key_success");
             let access_vector = KeyPermission::GET_INFO.0;
             let mut grant_keys = generate_ec_key_and_grant_to_users(
                 &keystore2,
@@ -633,7 +633,7 @@ fn keystore2_grant_key_to_multi_users_success() {
         run_as::run_as(GRANTOR_SU_CTX, Uid::from_raw(0), Gid::from_raw(0), || {
             let keystore2 = get_keystore_service();
             let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
-            let alias = format!("ks_grant_test_key_2{}", getuid());
+            let alias = test_alias("keystore2_grant_key_to_multi_users_success");
             let access_vector = KeyPermission::GET_INFO.0 | KeyPermission::USE.0;
 
             generate_ec_key_and_grant_to_users(
@@ -699,7 +699,9 @@ fn keystore2_grant_key_to_multi_users_delete_fails_with_key_not_found_error() {
         run_as::run_as(GRANTOR_SU_CTX, Uid::from_raw(0), Gid::from_raw(0), || {
             let keystore2 = get_keystore_service();
             let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
-            let alias = format!("ks_grant_test_key_2{}", getuid());
+            let alias = test_alias(
+                "keystore2_grant_key_to_multi_users_delete_fails_with_key_not_found_error",
+            );
             let access_vector =
                 KeyPermission::GET_INFO.0 | KeyPermission::USE.0 | KeyPermission::DELETE.0;
 
@@ -24,7 +24,7 @@ use keystore2_test_utils::{
     authorizations, get_keystore_service, key_generations, key_generations::Error,
 };
 
-use crate::keystore2_client_test_utils::perform_sample_sign_operation;
+use crate::keystore2_client_test_utils::{perform_sample_sign_operation, test_alias};
 
 /// Generate HMAC key with given parameters and perform a sample operation using generated key.
 fn create_hmac_key_and_operation(
@@ -303,3 +303,71 @@ fn keystore2_hmac_key_op_with_mac_len_less_than_min_mac_len_fail() {
         assert_eq!(Error::Km(ErrorCode::INVALID_MAC_LENGTH), result.unwrap_err());
     }
 }
+
+/// Longest message `assert_byte_by_byte_sign` feeds to the backend, to bound the number of
+/// `update` calls.
+const MAX_BYTE_BY_BYTE_MESSAGE_LEN: usize = 256;
+
+/// Compute an HMAC-SHA256 of `message` with the given key, either in a single `finish` call or by
+/// passing one byte per `update` call.
+fn compute_hmac(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    key: &KeyDescriptor,
+    message: &[u8],
+    byte_by_byte: bool,
+) -> Vec<u8> {
+    let op_response = sec_level
+        .createOperation(
+            key,
+            &authorizations::AuthSetBuilder::new()
+                .purpose(KeyPurpose::SIGN)
+                .digest(Digest::SHA_2_256)
+                .mac_length(256),
+            false,
+        )
+        .unwrap();
+    let op = op_response.iOperation.expect("Operation should have created successfully.");
+
+    let result = if byte_by_byte {
+        for byte in message.chunks(1) {
+            op.update(byte).unwrap();
+        }
+        op.finish(None, None)
+    } else {
+        op.finish(Some(message), None)
+    };
+    result.unwrap().expect("HMAC operation should return a MAC.")
+}
+
+/// Generate an HMAC key and sign `message`, truncated to `MAX_BYTE_BY_BYTE_MESSAGE_LEN`, once by
+/// feeding it one byte per `update` call and once in a single call. Both MACs must be equal.
+/// Returns the MAC.
+fn assert_byte_by_byte_sign(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    message: &[u8],
+) -> Vec<u8> {
+    let message = &message[..message.len().min(MAX_BYTE_BY_BYTE_MESSAGE_LEN)];
+    let alias = test_alias("assert_byte_by_byte_sign");
+    let key_metadata =
+        key_generations::generate_hmac_key(sec_level, &alias, 256, 256, Digest::SHA_2_256).unwrap();
+
+    let single_shot_mac = compute_hmac(sec_level, &key_metadata.key, message, false);
+    let byte_by_byte_mac = compute_hmac(sec_level, &key_metadata.key, message, true);
+    assert_eq!(
+        single_shot_mac, byte_by_byte_mac,
+        "MAC computed byte by byte differs from the single-shot MAC."
+    );
+    byte_by_byte_mac
+}
+
+/// Generate an HMAC key and sign a message by passing one byte per `update` call. Test should
+/// produce the same MAC as signing the message in a single call.
+#[test]
+fn keystore2_hmac_byte_by_byte_sign_matches_single_shot() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    let message: Vec<u8> = (0..=255).collect();
+    let mac = assert_byte_by_byte_sign(&sec_level, &message);
+    assert_eq!(32, mac.len());
+}
@@ -12,9 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use nix::unistd::getuid;
-
+use openssl::encrypt::Decrypter;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Public};
 use openssl::rand::rand_bytes;
+use openssl::rsa::Padding;
+use openssl::symm::{decrypt_aead, Cipher};
 use openssl::x509::X509;
 
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
@@ -37,9 +40,11 @@ use keystore2_test_utils::ffi_test_utils::{
 };
 
 use crate::keystore2_client_test_utils::{
-    encrypt_secure_key, encrypt_transport_key, get_vsr_api_level,
-    perform_sample_asym_sign_verify_op, perform_sample_hmac_sign_verify_op,
-    perform_sample_sym_key_decrypt_op, perform_sample_sym_key_encrypt_op, SAMPLE_PLAIN_TEXT,
+    assert_keyblob_opaque, assert_no_cert_for_symmetric, build_secure_key_wrapper,
+    encrypt_secure_key, encrypt_transport_key, generate_wrapping_key, get_vsr_api_level,
+    log_test_skip, perform_sample_asym_sign_verify_op, perform_sample_hmac_sign_verify_op,
+    perform_sample_sym_key_decrypt_op, perform_sample_sym_key_encrypt_op, test_alias,
+    SAMPLE_PLAIN_TEXT,
 };
 
 pub fn import_rsa_sign_key_and_perform_sample_operation(
@@ -92,7 +97,16 @@ fn perform_sym_key_encrypt_decrypt_op(
     assert_eq!(plain_text.unwrap(), SAMPLE_PLAIN_TEXT.to_vec());
 }
 
-fn build_secure_key_wrapper(
+/// Get the public key of the given wrapping key from its certificate.
+fn wrapping_public_key(wrapping_key_metadata: &KeyMetadata) -> PKey<Public> {
+    let cert_bytes = wrapping_key_metadata.certificate.as_ref().unwrap();
+    X509::from_der(cert_bytes).unwrap().public_key().unwrap()
+}
+
+/// Build a `SecureKeyWrapper` the way an app would, encrypting `secure_key` with the given
+/// transport key through keystore's own AES-GCM implementation, as opposed to
+/// `build_secure_key_wrapper` which encrypts it on the host.
+fn wrap_secure_key_with_transport_key(
     sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
     secure_key: &[u8],
     transport_key: &[u8],
@@ -101,7 +115,7 @@ fn build_secure_key_wrapper(
     wrapping_key_metadata: &KeyMetadata,
 ) -> Result<Vec<u8>, Error> {
     // Encrypt secure key with transport key.
-    let transport_key_alias = format!("ks_transport_key_aes_256_key_test_{}", getuid());
+    let transport_key_alias = test_alias("wrap_secure_key_with_transport_key");
     let transport_key_metadata =
         key_generations::import_transport_key(sec_level, Some(transport_key_alias), transport_key)
             .unwrap();
@@ -122,9 +136,7 @@ fn build_secure_key_wrapper(
     let encrypted_secure_key: Vec<u8> = encrypted_secure_key[0..secure_key.len()].to_vec();
 
     // Get wrapping key puplic part and encrypt the transport key.
-    let cert_bytes = wrapping_key_metadata.certificate.as_ref().unwrap();
-    let cert = X509::from_der(cert_bytes.as_ref()).unwrap();
-    let public_key = cert.public_key().unwrap();
+    let public_key = wrapping_public_key(wrapping_key_metadata);
     let encrypted_transport_key = encrypt_transport_key(transport_key, &public_key).unwrap();
 
     // Create `SecureKeyWrapper` ASN.1 DER-encoded data.
@@ -138,7 +150,7 @@ fn keystore2_rsa_import_key_success() {
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
 
-    let alias = format!("ks_rsa_key_test_import_1_{}{}", getuid(), 2048);
+    let alias = test_alias("keystore2_rsa_import_key_success");
 
     let import_params = authorizations::AuthSetBuilder::new()
         .no_auth_required()
@@ -170,7 +182,7 @@ fn keystore2_rsa_import_key_determine_key_size_and_pub_exponent() {
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
 
-    let alias = format!("ks_rsa_key_test_import_2_{}{}", getuid(), 2048);
+    let alias = test_alias("keystore2_rsa_import_key_determine_key_size_and_pub_exponent");
 
     // key-size and public-exponent shouldn't be specified in import key parameters list.
     let import_params = authorizations::AuthSetBuilder::new()
@@ -199,7 +211,7 @@ fn keystore2_rsa_import_key_fails_with_keysize_param_mismatch_error() {
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
 
-    let alias = format!("ks_rsa_key_test_import_3_{}{}", getuid(), 2048);
+    let alias = test_alias("keystore2_rsa_import_key_fails_with_keysize_param_mismatch_error");
 
     let import_params = authorizations::AuthSetBuilder::new()
         .no_auth_required()
@@ -232,7 +244,8 @@ fn keystore2_rsa_import_key_fails_with_public_exponent_param_mismatch_error() {
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
 
-    let alias = format!("ks_rsa_key_test_import_4_{}{}", getuid(), 2048);
+    let alias =
+        test_alias("keystore2_rsa_import_key_fails_with_public_exponent_param_mismatch_error");
 
     let import_params = authorizations::AuthSetBuilder::new()
         .no_auth_required()
@@ -266,7 +279,8 @@ fn keystore2_rsa_import_key_with_multipurpose_fails_incompt_purpose_error() {
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
 
-    let alias = format!("ks_rsa_key_test_import_5_{}{}", getuid(), 2048);
+    let alias =
+        test_alias("keystore2_rsa_import_key_with_multipurpose_fails_incompt_purpose_error");
 
     let import_params = authorizations::AuthSetBuilder::new()
         .no_auth_required()
@@ -304,7 +318,7 @@ fn keystore2_import_ec_key_success() {
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
 
-    let alias = format!("ks_ec_key_test_import_1_{}{}", getuid(), 256);
+    let alias = test_alias("keystore2_import_ec_key_success");
 
     if get_vsr_api_level() < 35 {
         // The KeyMint spec was previously not clear as to whether EC_CURVE was optional on import
@@ -342,7 +356,7 @@ fn keystore2_ec_import_key_fails_with_mismatch_curve_error() {
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
 
-    let alias = format!("ks_ec_key_test_import_1_{}{}", getuid(), 256);
+    let alias = test_alias("keystore2_ec_import_key_fails_with_mismatch_curve_error");
 
     let import_params = authorizations::AuthSetBuilder::new()
         .no_auth_required()
@@ -366,21 +380,22 @@ fn keystore2_ec_import_key_fails_with_mismatch_curve_error() {
 }
 
 /// Import AES key and verify key parameters. Try to create an operation using the imported key.
-/// Test should be able to create an operation successfully.
+/// Test should be able to create an operation successfully and receive no certificate.
 #[test]
 fn keystore2_import_aes_key_success() {
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
 
-    let alias = format!("ks_aes_key_test_import_1_{}{}", getuid(), 256);
+    let alias = test_alias("keystore2_import_aes_key_success");
     let key_metadata = key_generations::import_aes_key(&sec_level, Domain::APP, -1, Some(alias))
         .expect("Failed to import AES key.");
+    assert_no_cert_for_symmetric(&key_metadata);
 
     perform_sym_key_encrypt_decrypt_op(&sec_level, &key_metadata);
 }
 
 /// Import 3DES key and verify key parameters. Try to create an operation using the imported key.
-/// Test should be able to create an operation successfully.
+/// Test should be able to create an operation successfully and receive no certificate.
 #[test]
 fn keystore2_import_3des_key_success() {
     let keystore2 = get_keystore_service();
@@ -389,25 +404,27 @@ fn keystore2_import_3des_key_success() {
     )
     .unwrap();
 
-    let alias = format!("ks_3des_key_test_import_1_{}{}", getuid(), 168);
+    let alias = test_alias("keystore2_import_3des_key_success");
 
     let key_metadata = key_generations::import_3des_key(&sec_level, Domain::APP, -1, Some(alias))
         .expect("Failed to import 3DES key.");
+    assert_no_cert_for_symmetric(&key_metadata);
 
     perform_sym_key_encrypt_decrypt_op(&sec_level, &key_metadata);
 }
 
 /// Import HMAC key and verify key parameters. Try to create an operation using the imported key.
-/// Test should be able to create an operation successfully.
+/// Test should be able to create an operation successfully and receive no certificate.
 #[test]
 fn keystore2_import_hmac_key_success() {
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
 
-    let alias = format!("ks_hmac_key_test_import_1_{}", getuid());
+    let alias = test_alias("keystore2_import_hmac_key_success");
 
     let key_metadata = key_generations::import_hmac_key(&sec_level, Domain::APP, -1, Some(alias))
         .expect("Failed to import HMAC key.");
+    assert_no_cert_for_symmetric(&key_metadata);
 
     perform_sample_hmac_sign_verify_op(&sec_level, &key_metadata.key);
 }
@@ -432,7 +449,8 @@ fn keystore2_create_wrapped_key_and_import_wrapped_key_success() {
     rand_bytes(&mut nonce).unwrap();
 
     // Import wrapping key.
-    let wrapping_key_alias = format!("ks_wrapping_key_test_import_2_{}_2048", getuid());
+    let wrapping_key_alias =
+        test_alias("keystore2_create_wrapped_key_and_import_wrapped_key_success_wrapping_key");
     let wrapping_key_metadata = key_generations::import_wrapping_key(
         &sec_level,
         key_generations::RSA_2048_KEY,
@@ -445,7 +463,7 @@ fn keystore2_create_wrapped_key_and_import_wrapped_key_success() {
     let aad = create_wrapped_key_additional_auth_data().unwrap();
 
     // Build ASN.1 DER-encoded wrapped key material as described in `SecureKeyWrapper` schema.
-    let wrapped_key_data = build_secure_key_wrapper(
+    let wrapped_key_data = wrap_secure_key_with_transport_key(
         &sec_level,
         &secure_key,
         &transport_key,
@@ -456,7 +474,8 @@ fn keystore2_create_wrapped_key_and_import_wrapped_key_success() {
     .unwrap();
 
     // Unwrap the key. Import wrapped key.
-    let secured_key_alias = format!("ks_wrapped_aes_key_{}", getuid());
+    let secured_key_alias =
+        test_alias("keystore2_create_wrapped_key_and_import_wrapped_key_success");
     let secured_key_metadata = key_generations::import_wrapped_key(
         &sec_level,
         Some(secured_key_alias),
@@ -489,7 +508,9 @@ fn keystore2_create_wrapped_key_with_invalid_aad_and_import_wrapped_key_fail() {
     rand_bytes(&mut nonce).unwrap();
 
     // Import wrapping key.
-    let wrapping_key_alias = format!("ks_wrapping_key_test_import_2_{}_2048", getuid());
+    let wrapping_key_alias = test_alias(
+        "keystore2_create_wrapped_key_with_invalid_aad_and_import_wrapped_key_fail_wrapping_key",
+    );
     let wrapping_key_metadata = key_generations::import_wrapping_key(
         &sec_level,
         key_generations::RSA_2048_KEY,
@@ -501,7 +522,7 @@ fn keystore2_create_wrapped_key_with_invalid_aad_and_import_wrapped_key_fail() {
     let aad = b"foo";
 
     // Build ASN.1 DER-encoded wrapped key material as described in `SecureKeyWrapper` schema.
-    let wrapped_key_data = build_secure_key_wrapper(
+    let wrapped_key_data = wrap_secure_key_with_transport_key(
         &sec_level,
         &secure_key,
         &transport_key,
@@ -512,7 +533,8 @@ fn keystore2_create_wrapped_key_with_invalid_aad_and_import_wrapped_key_fail() {
     .unwrap();
 
     // Unwrap the key. Import wrapped key.
-    let secured_key_alias = format!("ks_wrapped_aes_key_{}", getuid());
+    let secured_key_alias =
+        test_alias("keystore2_create_wrapped_key_with_invalid_aad_and_import_wrapped_key_fail");
     let result = key_generations::map_ks_error(key_generations::import_wrapped_key(
         &sec_level,
         Some(secured_key_alias),
@@ -524,6 +546,268 @@ fn keystore2_create_wrapped_key_with_invalid_aad_and_import_wrapped_key_fail() {
     assert_eq!(Error::Km(ErrorCode::VERIFICATION_FAILED), result.unwrap_err());
 }
 
+/// Create a wrapped key data whose transport key isn't masked and try to import it with a
+/// mismatched, non-zero masking key. Unmasking yields a wrong transport key, so the test should
+/// fail to import the wrapped key with error code `VERIFICATION_FAILED`. Importing the same
+/// wrapped key with the matching all-zero masking key should succeed.
+#[test]
+fn keystore2_import_wrapped_key_with_mismatched_masking_key_fail() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    let mut secure_key = [0; 32];
+    rand_bytes(&mut secure_key).unwrap();
+
+    let mut transport_key = [0; 32];
+    rand_bytes(&mut transport_key).unwrap();
+
+    let mut nonce = [0; 12];
+    rand_bytes(&mut nonce).unwrap();
+
+    // Import wrapping key.
+    let wrapping_key_alias =
+        test_alias("keystore2_import_wrapped_key_with_mismatched_masking_key_fail_wrapping_key");
+    let wrapping_key_metadata = key_generations::import_wrapping_key(
+        &sec_level,
+        key_generations::RSA_2048_KEY,
+        Some(wrapping_key_alias),
+    )
+    .unwrap();
+
+    let aad = create_wrapped_key_additional_auth_data().unwrap();
+    let wrapped_key_data = wrap_secure_key_with_transport_key(
+        &sec_level,
+        &secure_key,
+        &transport_key,
+        &nonce,
+        &aad,
+        &wrapping_key_metadata,
+    )
+    .unwrap();
+
+    let unwrap_params = authorizations::AuthSetBuilder::new()
+        .digest(Digest::SHA_2_256)
+        .padding_mode(PaddingMode::RSA_OAEP);
+
+    let secured_key_alias =
+        test_alias("keystore2_import_wrapped_key_with_mismatched_masking_key_fail");
+    let result =
+        key_generations::map_ks_error(key_generations::import_wrapped_key_with_masking_key(
+            &sec_level,
+            Some(secured_key_alias.clone()),
+            &wrapping_key_metadata,
+            Some(wrapped_key_data.clone()),
+            Some(&[0xAA; 32]),
+            &unwrap_params,
+        ));
+    assert!(result.is_err());
+    assert_eq!(Error::Km(ErrorCode::VERIFICATION_FAILED), result.unwrap_err());
+
+    let secured_key_metadata = key_generations::import_wrapped_key_with_masking_key(
+        &sec_level,
+        Some(secured_key_alias),
+        &wrapping_key_metadata,
+        Some(wrapped_key_data),
+        Some(&[0; 32]),
+        &unwrap_params,
+    )
+    .expect("Failed to import wrapped key with matching masking key.");
+
+    perform_sym_key_encrypt_decrypt_op(&sec_level, &secured_key_metadata);
+}
+
+/// Split the DER-encoded TLV at the start of `data` into its tag, its contents and the bytes
+/// following it.
+fn split_der_tlv(data: &[u8]) -> (u8, &[u8], &[u8]) {
+    let (len, header_len) = match data[1] {
+        len if len < 0x80 => (len as usize, 2),
+        len_bytes => {
+            let len_bytes = (len_bytes & 0x7f) as usize;
+            let len =
+                data[2..2 + len_bytes].iter().fold(0usize, |acc, byte| (acc << 8) | *byte as usize);
+            (len, 2 + len_bytes)
+        }
+    };
+    (data[0], &data[header_len..header_len + len], &data[header_len + len..])
+}
+
+/// Build a `SecureKeyWrapper` with a masked transport key and decode it following the schema
+/// defined in IKeyMintDevice.aidl:
+///     SecureKeyWrapper ::= SEQUENCE {
+///         version INTEGER,
+///         encryptedTransportKey OCTET_STRING,
+///         initializationVector OCTET_STRING,
+///         keyDescription KeyDescription,
+///         encryptedKey OCTET_STRING,
+///         tag OCTET_STRING
+///     }
+/// Test should find version 0 and the given `KeyDescription`. Decrypting the transport key with
+/// the private wrapping key, unmasking it and decrypting the encrypted key with it should yield
+/// the wrapped key.
+#[test]
+fn keystore2_secure_key_wrapper_encoding_matches_spec() {
+    const SEQUENCE: u8 = 0x30;
+    const INTEGER: u8 = 0x02;
+    const OCTET_STRING: u8 = 0x04;
+
+    let wrapping_key = PKey::private_key_from_pkcs8(key_generations::RSA_2048_KEY).unwrap();
+    let public_key = PKey::public_key_from_der(&wrapping_key.public_key_to_der().unwrap()).unwrap();
+
+    let mut key_to_wrap = [0; 32];
+    rand_bytes(&mut key_to_wrap).unwrap();
+    let mut masking_key = [0; 32];
+    rand_bytes(&mut masking_key).unwrap();
+    let auth_params = create_wrapped_key_additional_auth_data().unwrap();
+
+    let wrapped_key_data =
+        build_secure_key_wrapper(&key_to_wrap, &public_key, &masking_key, &auth_params).unwrap();
+
+    let (tag, fields, rest) = split_der_tlv(&wrapped_key_data);
+    assert_eq!(SEQUENCE, tag);
+    assert!(rest.is_empty());
+    let (tag, version, fields) = split_der_tlv(fields);
+    assert_eq!((INTEGER, &[0u8][..]), (tag, version));
+    let (tag, encrypted_transport_key, fields) = split_der_tlv(fields);
+    assert_eq!(OCTET_STRING, tag);
+    let (tag, iv, fields) = split_der_tlv(fields);
+    assert_eq!(OCTET_STRING, tag);
+    assert_eq!(12, iv.len());
+    let (tag, _, rest) = split_der_tlv(fields);
+    assert_eq!(SEQUENCE, tag);
+    let key_description = &fields[..fields.len() - rest.len()];
+    assert_eq!(auth_params, key_description);
+    let (tag, encrypted_key, fields) = split_der_tlv(rest);
+    assert_eq!(OCTET_STRING, tag);
+    let (tag, gcm_tag, fields) = split_der_tlv(fields);
+    assert_eq!(OCTET_STRING, tag);
+    assert_eq!(16, gcm_tag.len());
+    assert!(fields.is_empty());
+
+    let mut decrypter = Decrypter::new(&wrapping_key).unwrap();
+    decrypter.set_rsa_padding(Padding::PKCS1_OAEP).unwrap();
+    decrypter.set_rsa_oaep_md(MessageDigest::sha256()).unwrap();
+    decrypter.set_rsa_mgf1_md(MessageDigest::sha1()).unwrap();
+    let mut masked_transport_key = vec![0; decrypter.decrypt_len(encrypted_transport_key).unwrap()];
+    let len = decrypter.decrypt(encrypted_transport_key, &mut masked_transport_key).unwrap();
+    masked_transport_key.truncate(len);
+    let transport_key: Vec<u8> =
+        masked_transport_key.iter().zip(&masking_key).map(|(t, m)| t ^ m).collect();
+
+    let unwrapped_key = decrypt_aead(
+        Cipher::aes_256_gcm(),
+        &transport_key,
+        Some(iv),
+        key_description,
+        encrypted_key,
+        gcm_tag,
+    )
+    .unwrap();
+    assert_eq!(key_to_wrap.to_vec(), unwrapped_key);
+}
+
+/// Build a `SecureKeyWrapper` whose transport key is masked with a random masking key and import
+/// it supplying the same masking key. Test should import the wrapped key successfully and perform
+/// crypto operations with it.
+#[test]
+fn keystore2_import_wrapped_key_with_masking_key_success() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    let mut secure_key = [0; 32];
+    rand_bytes(&mut secure_key).unwrap();
+
+    let mut masking_key = [0; 32];
+    rand_bytes(&mut masking_key).unwrap();
+
+    let wrapping_key_alias =
+        test_alias("keystore2_import_wrapped_key_with_masking_key_success_wrapping_key");
+    let wrapping_key_metadata = key_generations::import_wrapping_key(
+        &sec_level,
+        key_generations::RSA_2048_KEY,
+        Some(wrapping_key_alias),
+    )
+    .unwrap();
+
+    let aad = create_wrapped_key_additional_auth_data().unwrap();
+    let wrapped_key_data = build_secure_key_wrapper(
+        &secure_key,
+        &wrapping_public_key(&wrapping_key_metadata),
+        &masking_key,
+        &aad,
+    )
+    .unwrap();
+
+    let secured_key_alias = test_alias("keystore2_import_wrapped_key_with_masking_key_success");
+    let secured_key_metadata = key_generations::import_wrapped_key_with_masking_key(
+        &sec_level,
+        Some(secured_key_alias),
+        &wrapping_key_metadata,
+        Some(wrapped_key_data),
+        Some(&masking_key),
+        &authorizations::AuthSetBuilder::new()
+            .digest(Digest::SHA_2_256)
+            .padding_mode(PaddingMode::RSA_OAEP),
+    )
+    .expect("Failed to import wrapped key with masking key.");
+
+    perform_sym_key_encrypt_decrypt_op(&sec_level, &secured_key_metadata);
+}
+
+/// Generate a wrapping key instead of importing one and use it to import a wrapped AES key.
+/// Test should be able to import the wrapped key and perform crypto operations with it. The test
+/// is skipped on backends which don't support generating keys with `WRAP_KEY` purpose.
+#[test]
+fn keystore2_generated_wrapping_key_import_wrapped_key_success() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    let wrapping_key_alias =
+        test_alias("keystore2_generated_wrapping_key_import_wrapped_key_success_wrapping_key");
+    let (wrapping_key_metadata, public_key_der) =
+        match generate_wrapping_key(&sec_level, Some(wrapping_key_alias)) {
+            Ok(result) => result,
+            Err(Error::Km(ErrorCode::UNSUPPORTED_PURPOSE)) => {
+                log_test_skip("KeyMint does not support generating keys with `WRAP_KEY` purpose");
+                return;
+            }
+            Err(e) => panic!("Failed to generate wrapping key: {:?}", e),
+        };
+    let public_key = PKey::public_key_from_der(&public_key_der).unwrap();
+    assert_eq!(2048, public_key.bits());
+
+    let mut secure_key = [0; 32];
+    rand_bytes(&mut secure_key).unwrap();
+
+    let mut transport_key = [0; 32];
+    rand_bytes(&mut transport_key).unwrap();
+
+    let mut nonce = [0; 12];
+    rand_bytes(&mut nonce).unwrap();
+
+    let aad = create_wrapped_key_additional_auth_data().unwrap();
+    let wrapped_key_data = wrap_secure_key_with_transport_key(
+        &sec_level,
+        &secure_key,
+        &transport_key,
+        &nonce,
+        &aad,
+        &wrapping_key_metadata,
+    )
+    .unwrap();
+
+    let secured_key_alias =
+        test_alias("keystore2_generated_wrapping_key_import_wrapped_key_success");
+    let secured_key_metadata = key_generations::import_wrapped_key(
+        &sec_level,
+        Some(secured_key_alias),
+        &wrapping_key_metadata,
+        Some(wrapped_key_data),
+    )
+    .unwrap();
+
+    perform_sym_key_encrypt_decrypt_op(&sec_level, &secured_key_metadata);
+}
+
 /// Import wrapped AES key and use it for crypto operations. Test should import wrapped key and
 /// perform crypto operations successfully.
 #[test]
@@ -531,8 +815,8 @@ fn keystore2_import_wrapped_key_success() {
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
 
-    let alias = format!("ks_wrapped_key_test_import_1_{}_256", getuid());
-    let wrapping_key_alias = format!("ks_wrapping_key_test_import_1_{}_2048", getuid());
+    let alias = test_alias("keystore2_import_wrapped_key_success");
+    let wrapping_key_alias = test_alias("keystore2_import_wrapped_key_success_wrapping_key");
 
     let wrapping_key_params = authorizations::AuthSetBuilder::new()
         .no_auth_required()
@@ -570,8 +854,9 @@ fn keystore2_import_wrapped_key_fails_with_wrong_purpose() {
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
 
-    let wrapping_key_alias = format!("ks_wrapping_key_test_import_2_{}_2048", getuid());
-    let alias = format!("ks_wrapped_key_test_import_2_{}_256", getuid());
+    let wrapping_key_alias =
+        test_alias("keystore2_import_wrapped_key_fails_with_wrong_purpose_wrapping_key");
+    let alias = test_alias("keystore2_import_wrapped_key_fails_with_wrong_purpose");
 
     // In this KeyPurpose::WRAP_KEY is missing.
     let wrapping_key_params = authorizations::AuthSetBuilder::new()
@@ -616,10 +901,11 @@ fn keystore2_import_wrapped_key_fails_with_missing_wrapping_key() {
         authenticatorId: 0,
     }];
 
-    let alias = format!("ks_wrapped_key_test_import_3_{}_256", getuid());
+    let alias = test_alias("keystore2_import_wrapped_key_fails_with_missing_wrapping_key");
 
     // Wrapping key with this alias doesn't exist.
-    let wrapping_key_alias = format!("ks_wrapping_key_not_exist_{}_2048", getuid());
+    let wrapping_key_alias =
+        test_alias("keystore2_import_wrapped_key_fails_with_missing_wrapping_key_wrapping_key");
 
     let result = key_generations::map_ks_error(sec_level.importWrappedKey(
         &KeyDescriptor {
@@ -642,3 +928,40 @@ fn keystore2_import_wrapped_key_fails_with_missing_wrapping_key() {
     assert!(result.is_err());
     assert_eq!(Error::Rc(ResponseCode::KEY_NOT_FOUND), result.unwrap_err());
 }
+
+/// Import an AES key with a random secret using domain BLOB. Test should verify that neither the
+/// secret nor any half of it appears in plaintext in the returned key blob.
+#[test]
+fn keystore2_import_aes_key_blob_is_opaque() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    let mut aes_key = [0u8; 32];
+    rand_bytes(&mut aes_key).unwrap();
+
+    let import_params = authorizations::AuthSetBuilder::new()
+        .no_auth_required()
+        .algorithm(Algorithm::AES)
+        .block_mode(BlockMode::ECB)
+        .key_size(256)
+        .purpose(KeyPurpose::ENCRYPT)
+        .purpose(KeyPurpose::DECRYPT)
+        .padding_mode(PaddingMode::PKCS7);
+
+    let key_metadata = sec_level
+        .importKey(
+            &KeyDescriptor {
+                domain: Domain::BLOB,
+                nspace: key_generations::SELINUX_SHELL_NAMESPACE,
+                alias: None,
+                blob: None,
+            },
+            None,
+            &import_params,
+            0,
+            &aes_key,
+        )
+        .unwrap();
+
+    assert_keyblob_opaque(&key_metadata, &[&aes_key, &aes_key[..16], &aes_key[16..]]);
+}
@@ -12,18 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use nix::unistd::getuid;
-
 use openssl::ec::{EcGroup, EcKey};
 use openssl::error::ErrorStack;
 use openssl::nid::Nid;
-use openssl::pkey::{PKey, PKeyRef, Private, Public};
+use openssl::pkey::{Id, PKey, PKeyRef, Private, Public};
 use openssl::pkey_ctx::PkeyCtx;
 use openssl::x509::X509;
 
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
-    Digest::Digest, EcCurve::EcCurve, ErrorCode::ErrorCode, KeyPurpose::KeyPurpose,
-    SecurityLevel::SecurityLevel,
+    Algorithm::Algorithm, Digest::Digest, EcCurve::EcCurve, ErrorCode::ErrorCode,
+    KeyPurpose::KeyPurpose, SecurityLevel::SecurityLevel,
 };
 use android_system_keystore2::aidl::android::system::keystore2::{
     Domain::Domain, IKeystoreSecurityLevel::IKeystoreSecurityLevel, KeyDescriptor::KeyDescriptor,
@@ -34,6 +32,9 @@ use keystore2_test_utils::{
     authorizations, get_keystore_service, key_generations, key_generations::Error,
 };
 
+use crate::keystore2_client_test_utils::test_alias;
+use crate::skip_if_unsupported;
+
 /// This macro is used to verify that the key agreement works for the given curve.
 macro_rules! test_ec_key_agree {
     ( $test_name:ident, $ec_curve:expr ) => {
@@ -93,7 +94,7 @@ fn perform_ec_key_agreement(ec_curve: EcCurve) {
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
     let openssl_ec_curve = ec_curve_to_openrssl_curve_name(&ec_curve);
 
-    let alias = format!("ks_ec_test_key_agree_{}", getuid());
+    let alias = test_alias("perform_ec_key_agreement");
     let keymint_key = key_generations::generate_ec_agree_key(
         &sec_level,
         ec_curve,
@@ -126,7 +127,7 @@ fn keystore2_ec_25519_agree_key_success() {
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
 
-    let alias = format!("ks_ec_25519_test_key_agree_{}", getuid());
+    let alias = test_alias("keystore2_ec_25519_agree_key_success");
     let keymint_key = key_generations::generate_ec_agree_key(
         &sec_level,
         EcCurve::CURVE_25519,
@@ -152,7 +153,7 @@ fn keystore2_ec_agree_key_with_different_curves_fail() {
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
 
-    let alias = format!("ks_test_key_agree_fail{}", getuid());
+    let alias = test_alias("keystore2_ec_agree_key_with_different_curves_fail");
     let keymint_key = key_generations::generate_ec_agree_key(
         &sec_level,
         EcCurve::P_256,
@@ -177,3 +178,82 @@ fn keystore2_ec_agree_key_with_different_curves_fail() {
     assert!(result.is_err());
     assert_eq!(Error::Km(ErrorCode::INVALID_ARGUMENT), result.unwrap_err());
 }
+
+/// Alice's private key from RFC 7748 section 6.1.
+static RFC7748_ALICE_PRIVATE_KEY: &[u8] = &[
+    0x77, 0x07, 0x6d, 0x0a, 0x73, 0x18, 0xa5, 0x7d, 0x3c, 0x16, 0xc1, 0x72, 0x51, 0xb2, 0x66, 0x45,
+    0xdf, 0x4c, 0x2f, 0x87, 0xeb, 0xc0, 0x99, 0x2a, 0xb1, 0x77, 0xfb, 0xa5, 0x1d, 0xb9, 0x2c, 0x2a,
+];
+
+/// Bob's public key from RFC 7748 section 6.1.
+static RFC7748_BOB_PUBLIC_KEY: &[u8] = &[
+    0xde, 0x9e, 0xdb, 0x7d, 0x7b, 0x7d, 0xc1, 0xb4, 0xd3, 0x5b, 0x61, 0xc2, 0xec, 0xe4, 0x35, 0x37,
+    0x3f, 0x83, 0x43, 0xc8, 0x5b, 0x78, 0x67, 0x4d, 0xad, 0xfc, 0x7e, 0x14, 0x6f, 0x88, 0x2b, 0x4f,
+];
+
+/// Shared secret from RFC 7748 section 6.1.
+static RFC7748_SHARED_SECRET: &[u8] = &[
+    0x4a, 0x5d, 0x9d, 0x5b, 0xa4, 0xce, 0x2d, 0xe1, 0x72, 0x8e, 0x3b, 0xf4, 0x80, 0x35, 0x0f, 0x25,
+    0xe0, 0x7e, 0x21, 0xc9, 0x47, 0xd1, 0x9e, 0x33, 0x76, 0xf0, 0x9b, 0x3c, 0x1e, 0x16, 0x17, 0x42,
+];
+
+/// Import Alice's X25519 private key from RFC 7748 and perform key agreement with Bob's public
+/// key. The derived secret must equal the published shared secret. Returns `None` if the backend
+/// doesn't support importing `CURVE_25519` keys.
+fn assert_x25519_matches_rfc7748(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+) -> Option<Vec<u8>> {
+    let keystore2 = get_keystore_service();
+    let private_key =
+        PKey::private_key_from_raw_bytes(RFC7748_ALICE_PRIVATE_KEY, Id::X25519).unwrap();
+    let peer_key = PKey::public_key_from_raw_bytes(RFC7748_BOB_PUBLIC_KEY, Id::X25519).unwrap();
+
+    let alias = test_alias("assert_x25519_matches_rfc7748");
+    let import_params = authorizations::AuthSetBuilder::new()
+        .no_auth_required()
+        .algorithm(Algorithm::EC)
+        .ec_curve(EcCurve::CURVE_25519)
+        .purpose(KeyPurpose::AGREE_KEY);
+    let key_metadata = match key_generations::map_ks_error(sec_level.importKey(
+        &key_generations::make_key_descriptor(Domain::APP, -1, Some(alias.clone()), None),
+        None,
+        &import_params,
+        0,
+        &private_key.private_key_to_pkcs8().unwrap(),
+    )) {
+        Ok(key_metadata) => key_metadata,
+        Err(Error::Km(ErrorCode::UNSUPPORTED_EC_CURVE))
+        | Err(Error::Km(ErrorCode::UNSUPPORTED_KEY_FORMAT)) => return None,
+        Err(e) => panic!("Failed to import X25519 key: {:?}", e),
+    };
+
+    let authorizations = authorizations::AuthSetBuilder::new().purpose(KeyPurpose::AGREE_KEY);
+    let key_agree_op =
+        sec_level.createOperation(&key_metadata.key, &authorizations, false).unwrap();
+    let op = key_agree_op.iOperation.unwrap();
+    let secret = op.finish(Some(&peer_key.public_key_to_der().unwrap()), None).unwrap().unwrap();
+    keystore2
+        .deleteKey(&key_generations::make_key_descriptor(Domain::APP, -1, Some(alias), None))
+        .unwrap();
+
+    assert_eq!(
+        RFC7748_SHARED_SECRET,
+        secret.as_slice(),
+        "Expected shared secret {:02x?}, computed {:02x?}",
+        RFC7748_SHARED_SECRET,
+        secret
+    );
+    Some(secret)
+}
+
+/// Import the RFC 7748 X25519 test key and perform key agreement with the RFC 7748 peer key. Test
+/// should derive the published shared secret, or skip if the backend doesn't support
+/// `CURVE_25519`.
+#[test]
+fn keystore2_x25519_agree_key_rfc7748_vector() {
+    skip_if_unsupported!(curve_25519);
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    assert_x25519_matches_rfc7748(&sec_level);
+}
@@ -12,20 +12,21 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use nix::unistd::getuid;
-
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     Digest::Digest, EcCurve::EcCurve, KeyPurpose::KeyPurpose, SecurityLevel::SecurityLevel,
 };
 use android_system_keystore2::aidl::android::system::keystore2::{
-    Domain::Domain, KeyDescriptor::KeyDescriptor, ResponseCode::ResponseCode,
+    Domain::Domain, IKeystoreSecurityLevel::IKeystoreSecurityLevel, KeyDescriptor::KeyDescriptor,
+    ResponseCode::ResponseCode,
 };
 
 use keystore2_test_utils::{
-    authorizations, get_keystore_service, key_generations, key_generations::Error,
+    authorizations, get_keystore_service, key_generations, key_generations::Error, KeystoreClient,
 };
 
-use crate::keystore2_client_test_utils::perform_sample_sign_operation;
+use crate::keystore2_client_test_utils::{
+    delete_app_key, perform_sample_sign_operation, restart_keystore2_service, test_alias,
+};
 
 /// Try to generate a key with `Domain::KEY_ID`, test should fail with an error code
 /// `SYSTEM_ERROR`. `Domain::KEY_ID` is not allowed to use for generating a key. Key id is returned
@@ -112,7 +113,7 @@ fn keystore2_find_key_with_key_id_as_domain() {
 fn keystore2_key_id_alias_rebind_verify_by_alias() {
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
-    let alias = format!("ks_key_id_test_alias_rebind_1_{}", getuid());
+    let alias = test_alias("keystore2_key_id_alias_rebind_verify_by_alias");
 
     let key_metadata = key_generations::generate_ec_key(
         &sec_level,
@@ -179,7 +180,7 @@ fn keystore2_key_id_alias_rebind_verify_by_alias() {
 fn keystore2_key_id_alias_rebind_verify_by_key_id() {
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
-    let alias = format!("ks_key_id_test_alias_rebind_2_{}", getuid());
+    let alias = test_alias("keystore2_key_id_alias_rebind_verify_by_key_id");
 
     let key_metadata = key_generations::generate_ec_key(
         &sec_level,
@@ -255,3 +256,162 @@ fn keystore2_key_id_alias_rebind_verify_by_key_id() {
         ))
     );
 }
+
+/// Generate a key with the given alias, restart the keystore2 service and load the key again by
+/// its alias through a freshly acquired service handle. The key must be still present with the
+/// same key id and usable for an operation. Panics reporting the vanished key otherwise.
+fn assert_key_survives_restart(alias: &str) {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+    let key_metadata = key_generations::generate_ec_key(
+        &sec_level,
+        Domain::APP,
+        -1,
+        Some(alias.to_string()),
+        EcCurve::P_256,
+        Digest::SHA_2_256,
+    )
+    .expect("Failed to generate a EC key.");
+
+    restart_keystore2_service();
+
+    // The old handles died with the service, reacquire them.
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+    let key_entry_response =
+        match key_generations::map_ks_error(keystore2.getKeyEntry(&KeyDescriptor {
+            domain: Domain::APP,
+            nspace: -1,
+            alias: Some(alias.to_string()),
+            blob: None,
+        })) {
+            Ok(response) => response,
+            Err(Error::Rc(ResponseCode::KEY_NOT_FOUND)) => {
+                panic!("Key {alias} vanished after keystore2 restart.")
+            }
+            Err(e) => panic!("Failed to load key {alias} after keystore2 restart: {e:?}"),
+        };
+    assert_eq!(key_metadata.key.nspace, key_entry_response.metadata.key.nspace);
+    assert_eq!(key_metadata.certificate, key_entry_response.metadata.certificate);
+
+    let op_response = sec_level
+        .createOperation(
+            &key_entry_response.metadata.key,
+            &authorizations::AuthSetBuilder::new()
+                .purpose(KeyPurpose::SIGN)
+                .digest(Digest::SHA_2_256),
+            false,
+        )
+        .expect("Error in creation of operation.");
+    assert_eq!(
+        Ok(()),
+        key_generations::map_ks_error(perform_sample_sign_operation(
+            &op_response.iOperation.unwrap()
+        ))
+    );
+}
+
+/// Generate a key, restart the keystore2 service and verify that the key is still present and
+/// usable. This verifies that keys are durably stored.
+#[test]
+fn keystore2_key_survives_service_restart() {
+    let alias = test_alias("keystore2_key_survives_service_restart");
+    assert_key_survives_restart(&alias);
+    delete_app_key(&get_keystore_service(), &alias).unwrap();
+}
+
+/// Generate a key, restart the keystore2 service and look up the key through a `KeystoreClient`
+/// which was connected before the restart. The client should reconnect transparently and find
+/// the key.
+#[test]
+fn keystore2_client_reconnects_after_service_restart() {
+    let alias = test_alias("keystore2_client_reconnects_after_service_restart");
+    let client = KeystoreClient::new();
+    let sec_level = client.get_security_level(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+    let key_metadata = key_generations::generate_ec_p256_signing_key(
+        &sec_level,
+        Domain::APP,
+        -1,
+        Some(alias.clone()),
+        None,
+    )
+    .unwrap();
+
+    restart_keystore2_service();
+
+    let key =
+        KeyDescriptor { domain: Domain::APP, nspace: -1, alias: Some(alias.clone()), blob: None };
+    let key_entry_response =
+        client.get_key_entry(&key).expect("Client failed to reconnect after restart.");
+    assert_eq!(key_metadata.key.nspace, key_entry_response.metadata.key.nspace);
+    client.get_security_level(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    client.delete_key(&key).unwrap();
+}
+
+/// Generate a key with the given alias and then generate another key with the same alias. The
+/// alias must be rebound to the new key, which is usable, while the replaced key is no longer
+/// reachable through its key id. The alias must be listed only once. Returns whether the replaced
+/// key unexpectedly survived.
+fn assert_alias_overwrite_behavior(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    alias: &str,
+) -> bool {
+    let keystore2 = get_keystore_service();
+    let op_params =
+        authorizations::AuthSetBuilder::new().purpose(KeyPurpose::SIGN).digest(Digest::SHA_2_256);
+
+    let old_key_metadata = key_generations::generate_ec_p256_signing_key(
+        sec_level,
+        Domain::APP,
+        -1,
+        Some(alias.to_string()),
+        None,
+    )
+    .unwrap();
+    let new_key_metadata = key_generations::generate_ec_p256_signing_key(
+        sec_level,
+        Domain::APP,
+        -1,
+        Some(alias.to_string()),
+        None,
+    )
+    .unwrap();
+    assert_ne!(old_key_metadata.key.nspace, new_key_metadata.key.nspace);
+
+    let old_key_result = key_generations::map_ks_error(sec_level.createOperation(
+        &old_key_metadata.key,
+        &op_params,
+        false,
+    ));
+    let old_key_survived = match old_key_result {
+        Err(Error::Rc(ResponseCode::KEY_NOT_FOUND)) => false,
+        Ok(_) => true,
+        Err(e) => panic!("Unexpected error using the replaced key: {:?}", e),
+    };
+
+    let op_response = sec_level.createOperation(&new_key_metadata.key, &op_params, false).unwrap();
+    assert_eq!(
+        Ok(()),
+        key_generations::map_ks_error(perform_sample_sign_operation(
+            &op_response.iOperation.unwrap()
+        ))
+    );
+
+    let entries = keystore2.listEntries(Domain::APP, -1).unwrap();
+    assert_eq!(1, entries.iter().filter(|key| key.alias.as_deref() == Some(alias)).count());
+
+    delete_app_key(&keystore2, alias).unwrap();
+    old_key_survived
+}
+
+/// Generate two keys with the same alias. Test should verify that the second generation replaces
+/// the first key instead of failing or keeping both keys.
+#[test]
+fn keystore2_key_gen_same_alias_overwrites_key() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+    let alias = test_alias("keystore2_key_gen_same_alias_overwrites_key");
+
+    assert!(!assert_alias_overwrite_behavior(&sec_level, &alias), "Replaced key is still usable.");
+}
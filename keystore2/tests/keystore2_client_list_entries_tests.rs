@@ -12,19 +12,27 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use nix::unistd::{getuid, Gid, Uid};
+use nix::unistd::{Gid, Uid};
 use rustutils::users::AID_USER_OFFSET;
 use std::collections::HashSet;
 use std::fmt::Write;
+use std::thread;
 
-use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Digest::Digest, KeyPurpose::KeyPurpose, SecurityLevel::SecurityLevel,
+};
 use android_system_keystore2::aidl::android::system::keystore2::{
-    Domain::Domain, IKeystoreService::IKeystoreService, KeyDescriptor::KeyDescriptor,
-    KeyPermission::KeyPermission, ResponseCode::ResponseCode,
+    Domain::Domain, IKeystoreSecurityLevel::IKeystoreSecurityLevel,
+    IKeystoreService::IKeystoreService, KeyDescriptor::KeyDescriptor, KeyPermission::KeyPermission,
+    ResponseCode::ResponseCode,
 };
 
-use crate::keystore2_client_test_utils::{delete_all_entries, delete_app_key, verify_aliases};
-use keystore2_test_utils::{get_keystore_service, key_generations, key_generations::Error, run_as};
+use crate::keystore2_client_test_utils::{
+    delete_all_entries, delete_app_key, perform_sample_sign_operation, test_alias, verify_aliases,
+};
+use keystore2_test_utils::{
+    authorizations, get_keystore_service, key_generations, key_generations::Error, run_as,
+};
 
 /// Try to find a key with given key parameters using `listEntries` API.
 fn key_alias_exists(
@@ -43,6 +51,101 @@ fn key_alias_exists(
     alias_count != 0
 }
 
+/// Generate `keys_per_thread` EC keys from each of `threads` threads in parallel. Afterwards verify
+/// that every generated key is listed by `listEntries`, can be loaded and can be used for a sample
+/// sign operation. Returns the aliases of the keys which failed any of these checks.
+fn assert_no_db_corruption_under_load(
+    keystore2: &binder::Strong<dyn IKeystoreService>,
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    threads: usize,
+    keys_per_thread: usize,
+) -> Vec<String> {
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let sec_level = sec_level.clone();
+            thread::spawn(move || {
+                (0..keys_per_thread)
+                    .map(|k| {
+                        let alias =
+                            test_alias(&format!("assert_no_db_corruption_under_load_{}_{}", t, k));
+                        key_generations::generate_ec_p256_signing_key(
+                            &sec_level,
+                            Domain::APP,
+                            -1,
+                            Some(alias.clone()),
+                            None,
+                        )
+                        .unwrap();
+                        alias
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+    let aliases: Vec<String> =
+        handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect();
+
+    let listed: HashSet<String> = keystore2
+        .listEntries(Domain::APP, -1)
+        .unwrap()
+        .into_iter()
+        .filter_map(|key| key.alias)
+        .collect();
+
+    aliases
+        .into_iter()
+        .filter(|alias| {
+            let key = KeyDescriptor {
+                domain: Domain::APP,
+                nspace: -1,
+                alias: Some(alias.to_string()),
+                blob: None,
+            };
+            let usable = keystore2.getKeyEntry(&key).is_ok()
+                && sec_level
+                    .createOperation(
+                        &key,
+                        &authorizations::AuthSetBuilder::new()
+                            .purpose(KeyPurpose::SIGN)
+                            .digest(Digest::SHA_2_256),
+                        false,
+                    )
+                    .ok()
+                    .and_then(|op_response| op_response.iOperation)
+                    .is_some_and(|op| perform_sample_sign_operation(&op).is_ok());
+            !(listed.contains(alias) && usable)
+        })
+        .collect()
+}
+
+/// Generate keys from multiple threads in parallel in a user context. Test should be able to
+/// list, load and use every generated key afterwards.
+#[test]
+fn keystore2_list_entries_after_parallel_key_generation_success() {
+    static CLIENT_CTX: &str = "u:r:untrusted_app:s0:c91,c256,c10,c20";
+
+    const USER_ID: u32 = 97;
+    const APPLICATION_ID: u32 = 10007;
+    static CLIENT_UID: u32 = USER_ID * AID_USER_OFFSET + APPLICATION_ID;
+    static CLIENT_GID: u32 = CLIENT_UID;
+
+    // SAFETY: The test is run in a separate process with no other threads.
+    unsafe {
+        run_as::run_as(CLIENT_CTX, Uid::from_raw(CLIENT_UID), Gid::from_raw(CLIENT_GID), || {
+            let keystore2 = get_keystore_service();
+            let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+            // Make sure there are no keystore entries exist before adding new entries.
+            delete_all_entries(&keystore2);
+
+            let failed = assert_no_db_corruption_under_load(&keystore2, &sec_level, 4, 10);
+            assert!(failed.is_empty(), "Keys failed the usability check: {:?}", failed);
+
+            delete_all_entries(&keystore2);
+        })
+    };
+}
+
 /// List key entries with domain as SELINUX and APP.
 /// 1. Generate a key with domain as SELINUX and find this key entry in list of keys retrieved from
 ///    `listEntries` with domain SELINUX. Test should be able find this key entry successfully.
@@ -66,7 +169,7 @@ fn keystore2_list_entries_success() {
             let keystore2 = get_keystore_service();
             let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
 
-            let alias = format!("list_entries_grant_key1_{}", getuid());
+            let alias = test_alias("keystore2_list_entries_success_grant");
 
             // Make sure there is no key exist with this `alias` in `SELINUX` domain and
             // `SELINUX_SHELL_NAMESPACE` namespace.
@@ -124,7 +227,7 @@ fn keystore2_list_entries_success() {
                 let keystore2 = get_keystore_service();
                 let sec_level =
                     keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
-                let alias = format!("list_entries_success_key{}", getuid());
+                let alias = test_alias("keystore2_list_entries_success");
 
                 let key_metadata = key_generations::generate_ec_p256_signing_key(
                     &sec_level,
@@ -177,6 +280,102 @@ fn keystore2_list_entries_fails_perm_denied() {
     };
 }
 
+/// Generate a key in the given SELinux namespace with `su` context, then try to generate, list,
+/// load and delete keys in that namespace from the given context, which must lack access to it.
+/// Returns the name of each attempted call together with the service specific error code it
+/// failed with, or `None` if the call unexpectedly succeeded.
+///
+/// # Safety
+///
+/// Must be called from a process with no other threads.
+unsafe fn assert_namespace_access_denied(
+    ctx: &'static str,
+    nspace: i64,
+) -> Vec<(String, Option<i32>)> {
+    let auid = 91 * AID_USER_OFFSET + 10001;
+    let agid = 91 * AID_USER_OFFSET + 10001;
+    let alias = test_alias(&format!("assert_namespace_access_denied_{}", nspace));
+    let key =
+        KeyDescriptor { domain: Domain::SELINUX, nspace, alias: Some(alias.clone()), blob: None };
+
+    let owner_key = key.clone();
+    // SAFETY: The caller guarantees that there are no other threads.
+    unsafe {
+        run_as::run_as(
+            key_generations::TARGET_SU_CTX,
+            Uid::from_raw(0),
+            Gid::from_raw(0),
+            move || {
+                let keystore2 = get_keystore_service();
+                let sec_level =
+                    keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+                key_generations::generate_ec_p256_signing_key(
+                    &sec_level,
+                    owner_key.domain,
+                    owner_key.nspace,
+                    owner_key.alias,
+                    None,
+                )
+                .unwrap();
+            },
+        )
+    };
+
+    let denied_key = key.clone();
+    // SAFETY: The caller guarantees that there are no other threads.
+    let results = unsafe {
+        run_as::run_as(ctx, Uid::from_raw(auid), Gid::from_raw(agid), move || {
+            let keystore2 = get_keystore_service();
+            let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+            let generate_result = key_generations::generate_ec_p256_signing_key(
+                &sec_level,
+                Domain::SELINUX,
+                nspace,
+                Some(format!("{}_new", alias)),
+                None,
+            );
+
+            [
+                ("generateKey", generate_result.err()),
+                ("listEntries", keystore2.listEntries(Domain::SELINUX, nspace).err()),
+                ("getKeyEntry", keystore2.getKeyEntry(&denied_key).err()),
+                ("deleteKey", keystore2.deleteKey(&denied_key).err()),
+            ]
+            .into_iter()
+            .map(|(call, status)| (call.to_string(), status.map(|s| s.service_specific_error())))
+            .collect::<Vec<_>>()
+        })
+    };
+
+    // SAFETY: The caller guarantees that there are no other threads.
+    unsafe {
+        run_as::run_as(
+            key_generations::TARGET_SU_CTX,
+            Uid::from_raw(0),
+            Gid::from_raw(0),
+            move || {
+                get_keystore_service().deleteKey(&key).unwrap();
+            },
+        )
+    };
+    results
+}
+
+/// Try to generate, list, load and delete keys in the shell SELinux namespace from an app context
+/// which has no access to that namespace. Test should fail every call with `PERMISSION_DENIED`.
+#[test]
+fn keystore2_selinux_namespace_access_denied() {
+    static TARGET_CTX: &str = "u:r:untrusted_app:s0:c91,c256,c10,c20";
+
+    // SAFETY: The test is run in a separate process with no other threads.
+    let results = unsafe {
+        assert_namespace_access_denied(TARGET_CTX, key_generations::SELINUX_SHELL_NAMESPACE)
+    };
+    for (call, error_code) in results {
+        assert_eq!(Some(ResponseCode::PERMISSION_DENIED.0), error_code, "{} was not denied", call);
+    }
+}
+
 /// Try to list key entries with domain BLOB. Test should fail with error repose code
 /// `INVALID_ARGUMENT`.
 #[test]
@@ -723,3 +922,108 @@ fn keystore2_get_number_of_entries_fails_invalid_arg() {
     assert!(result.is_err());
     assert_eq!(Error::Rc(ResponseCode::INVALID_ARGUMENT), result.unwrap_err());
 }
+
+/// Generate keys with aliases of growing length until key generation fails or the longest probed
+/// length is reached. Every key which was generated must be found by its alias and is deleted
+/// again. Returns the largest alias length which was accepted.
+fn assert_max_alias_length(sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>) -> usize {
+    const ALIAS_LENGTHS: &[usize] = &[16, 64, 256, 1024, 4096, 16384, 65536];
+    let keystore2 = get_keystore_service();
+
+    let mut max_accepted_len = 0;
+    for alias_len in ALIAS_LENGTHS {
+        let alias = "a".repeat(*alias_len);
+        let result = key_generations::map_ks_error(key_generations::generate_ec_p256_signing_key(
+            sec_level,
+            Domain::APP,
+            -1,
+            Some(alias.clone()),
+            None,
+        ));
+        if result.is_err() {
+            break;
+        }
+        assert!(key_alias_exists(&keystore2, Domain::APP, -1, alias.clone()));
+        delete_app_key(&keystore2, &alias).unwrap();
+        max_accepted_len = *alias_len;
+    }
+    max_accepted_len
+}
+
+/// Generate keys with increasingly long aliases. Test should accept aliases of at least a few
+/// hundred characters and store them without truncation.
+#[test]
+fn keystore2_max_alias_length() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    let max_alias_len = assert_max_alias_length(&sec_level);
+    assert!(max_alias_len >= 256);
+}
+
+/// Outcome of generating a key with an unusual alias.
+#[derive(Debug, PartialEq)]
+enum AliasHandling {
+    /// Key generation was rejected.
+    Rejected,
+    /// The key was found again by exactly the same alias.
+    RoundTrip,
+    /// The key was generated but its alias was listed differently or it couldn't be loaded.
+    Corrupted,
+}
+
+/// Generate keys with aliases containing an embedded NUL character, non-ASCII characters and
+/// characters outside the basic multilingual plane. Aliases are strings in the keystore2 API, so
+/// arbitrary non-UTF-8 byte sequences can't be expressed; these are the encoding edge cases that
+/// can. Each alias must either be rejected or round-trip exactly. Generated keys are deleted.
+fn assert_binary_alias_handling(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+) -> Vec<(String, AliasHandling)> {
+    let keystore2 = get_keystore_service();
+    let aliases =
+        ["ks_alias_nul_\u{0}_test", "ks_alias_\u{e4}\u{f6}\u{fc}_test", "ks_alias_\u{1f511}_test"];
+
+    aliases
+        .into_iter()
+        .map(|alias| {
+            let result = key_generations::generate_ec_p256_signing_key(
+                sec_level,
+                Domain::APP,
+                -1,
+                Some(alias.to_string()),
+                None,
+            );
+            if result.is_err() {
+                return (alias.to_string(), AliasHandling::Rejected);
+            }
+
+            let listed = key_alias_exists(&keystore2, Domain::APP, -1, alias.to_string());
+            let loaded = keystore2
+                .getKeyEntry(&key_generations::make_key_descriptor(
+                    Domain::APP,
+                    -1,
+                    Some(alias.to_string()),
+                    None,
+                ))
+                .is_ok();
+            let _ = delete_app_key(&keystore2, alias);
+            let handling =
+                if listed && loaded { AliasHandling::RoundTrip } else { AliasHandling::Corrupted };
+            (alias.to_string(), handling)
+        })
+        .collect()
+}
+
+/// Generate keys with aliases containing unusual characters. Test should either reject an alias
+/// or find the key again by exactly the same alias.
+#[test]
+fn keystore2_binary_alias_handling() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    let results = assert_binary_alias_handling(&sec_level);
+    assert_eq!(3, results.len());
+    for (alias, handling) in results {
+        assert_ne!(AliasHandling::Corrupted, handling, "Alias {:?} did not round-trip.", alias);
+    }
+}
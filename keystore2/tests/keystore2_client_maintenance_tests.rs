@@ -0,0 +1,346 @@
+// Copyright 2026, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use nix::unistd::{Gid, Uid};
+use rustutils::users::AID_USER_OFFSET;
+
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, Digest::Digest, EcCurve::EcCurve, ErrorCode::ErrorCode,
+    KeyPurpose::KeyPurpose, SecurityLevel::SecurityLevel,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor, ResponseCode::ResponseCode,
+};
+
+use keystore2_test_utils::{
+    authorizations, get_keystore_auth_service, get_keystore_maintenance_service,
+    get_keystore_service, key_generations, run_as,
+};
+
+use crate::keystore2_client_test_utils::{
+    assert_namespace_cleared, clear_namespace, delete_app_key, perform_sample_sign_operation,
+    test_alias,
+};
+
+static TARGET_CTX: &str = "u:r:untrusted_app:s0:c91,c256,c10,c20";
+static PASSWORD: &[u8] = b"ks_maintenance_test_password";
+
+/// Remove the given user if it exists, then add it again and initialize its super keys with
+/// `PASSWORD`, leaving the user unlocked.
+///
+/// # Safety
+///
+/// Must be called from a process with no other threads.
+unsafe fn setup_unlocked_user(user_id: i32) {
+    // SAFETY: The caller guarantees that there are no other threads.
+    unsafe {
+        run_as::run_as(
+            key_generations::TARGET_SU_CTX,
+            Uid::from_raw(0),
+            Gid::from_raw(0),
+            move || {
+                let maint_service = get_keystore_maintenance_service();
+                // Clear state left behind by a previous run.
+                maint_service.onUserRemoved(user_id).expect("Failed to remove user.");
+                maint_service.onUserAdded(user_id).expect("Failed to add user.");
+                maint_service
+                    .initUserSuperKeys(user_id, PASSWORD, true)
+                    .expect("Failed to initialize super keys.");
+                get_keystore_auth_service()
+                    .onDeviceUnlocked(user_id, Some(PASSWORD))
+                    .expect("Failed to unlock user.");
+            },
+        )
+    };
+}
+
+/// Lock or unlock the given user with `su` context.
+///
+/// # Safety
+///
+/// Must be called from a process with no other threads.
+unsafe fn set_user_locked(user_id: i32, locked: bool) {
+    // SAFETY: The caller guarantees that there are no other threads.
+    unsafe {
+        run_as::run_as(
+            key_generations::TARGET_SU_CTX,
+            Uid::from_raw(0),
+            Gid::from_raw(0),
+            move || {
+                let auth_service = get_keystore_auth_service();
+                if locked {
+                    auth_service.onDeviceLocked(user_id, &[], false).expect("Failed to lock user.");
+                } else {
+                    auth_service
+                        .onDeviceUnlocked(user_id, Some(PASSWORD))
+                        .expect("Failed to unlock user.");
+                }
+            },
+        )
+    };
+}
+
+/// Remove the given user with `su` context.
+///
+/// # Safety
+///
+/// Must be called from a process with no other threads.
+unsafe fn remove_user(user_id: i32) {
+    // SAFETY: The caller guarantees that there are no other threads.
+    unsafe {
+        run_as::run_as(
+            key_generations::TARGET_SU_CTX,
+            Uid::from_raw(0),
+            Gid::from_raw(0),
+            move || {
+                get_keystore_maintenance_service()
+                    .onUserRemoved(user_id)
+                    .expect("Failed to remove user.");
+            },
+        )
+    };
+}
+
+/// Try to perform a sample sign operation with the given key as an app in the given user.
+/// Returns the service specific error code of the failing call, if any.
+///
+/// # Safety
+///
+/// Must be called from a process with no other threads.
+unsafe fn sign_as_user(user_id: i32, alias: &str) -> Option<i32> {
+    let uid = user_id as u32 * AID_USER_OFFSET + 10001;
+    let alias = alias.to_string();
+    // SAFETY: The caller guarantees that there are no other threads.
+    unsafe {
+        run_as::run_as(TARGET_CTX, Uid::from_raw(uid), Gid::from_raw(uid), move || {
+            let keystore2 = get_keystore_service();
+            let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+            let key =
+                KeyDescriptor { domain: Domain::APP, nspace: -1, alias: Some(alias), blob: None };
+            sec_level
+                .createOperation(
+                    &key,
+                    &authorizations::AuthSetBuilder::new()
+                        .purpose(KeyPurpose::SIGN)
+                        .digest(Digest::SHA_2_256),
+                    false,
+                )
+                .and_then(|op_response| {
+                    perform_sample_sign_operation(&op_response.iOperation.unwrap())
+                })
+                .err()
+                .map(|e| e.service_specific_error())
+        })
+    }
+}
+
+/// Set up an unlocked user and generate an `UNLOCKED_DEVICE_REQUIRED` key as an app in that user,
+/// which keystore protects with the user's super key. Then lock the user and verify that the key
+/// can't be used until the user is unlocked again. Returns the service specific error code
+/// reported while the user was locked.
+///
+/// # Safety
+///
+/// Must be called from a process with no other threads.
+unsafe fn assert_superencrypted_key_requires_unlock(alias: &str) -> i32 {
+    const USER_ID: i32 = 96;
+    let uid = USER_ID as u32 * AID_USER_OFFSET + 10001;
+
+    // SAFETY: The caller guarantees that there are no other threads.
+    unsafe { setup_unlocked_user(USER_ID) };
+
+    let gen_alias = alias.to_string();
+    // SAFETY: The caller guarantees that there are no other threads.
+    unsafe {
+        run_as::run_as(TARGET_CTX, Uid::from_raw(uid), Gid::from_raw(uid), move || {
+            let keystore2 = get_keystore_service();
+            let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+            let gen_params = authorizations::AuthSetBuilder::new()
+                .no_auth_required()
+                .unlocked_device_required()
+                .algorithm(Algorithm::EC)
+                .purpose(KeyPurpose::SIGN)
+                .purpose(KeyPurpose::VERIFY)
+                .digest(Digest::SHA_2_256)
+                .ec_curve(EcCurve::P_256);
+            key_generations::generate_key(&sec_level, &gen_params, &gen_alias).unwrap();
+        })
+    };
+
+    // SAFETY: The caller guarantees that there are no other threads.
+    unsafe {
+        assert_eq!(None, sign_as_user(USER_ID, alias), "Key unusable while user is unlocked.");
+
+        set_user_locked(USER_ID, true);
+        let locked_error = sign_as_user(USER_ID, alias);
+
+        set_user_locked(USER_ID, false);
+        assert_eq!(None, sign_as_user(USER_ID, alias), "Key unusable after unlocking the user.");
+
+        let del_alias = alias.to_string();
+        run_as::run_as(TARGET_CTX, Uid::from_raw(uid), Gid::from_raw(uid), move || {
+            delete_app_key(&get_keystore_service(), &del_alias).unwrap();
+        });
+        remove_user(USER_ID);
+
+        locked_error.expect("Key was usable while the user was locked.")
+    }
+}
+
+/// Generate an `UNLOCKED_DEVICE_REQUIRED` key in a freshly set up user and try to use it while
+/// the user is locked. Test should fail to use the key with `DEVICE_LOCKED` or `LOCKED` error
+/// until the user is unlocked again.
+#[test]
+fn keystore2_superencrypted_key_requires_unlock() {
+    let alias = test_alias("keystore2_superencrypted_key_requires_unlock");
+    // SAFETY: The test is run in a separate process with no other threads.
+    let error_code = unsafe { assert_superencrypted_key_requires_unlock(&alias) };
+    assert!(
+        error_code == ErrorCode::DEVICE_LOCKED.0 || error_code == ResponseCode::LOCKED.0,
+        "Unexpected error code while locked: {}",
+        error_code
+    );
+}
+
+/// Add the given user, generate a few keys as an app in that user and then remove the user using
+/// the maintenance `onUserRemoved` API. Returns the aliases of the keys which survived the user
+/// removal, i.e. which are still listed or can still be loaded.
+///
+/// # Safety
+///
+/// Must be called from a process with no other threads.
+unsafe fn assert_user_removal_deletes_keys(user_id: i32) -> Vec<String> {
+    const KEY_COUNT: usize = 3;
+    let uid = user_id as u32 * AID_USER_OFFSET + 10001;
+
+    // SAFETY: The caller guarantees that there are no other threads.
+    unsafe {
+        run_as::run_as(
+            key_generations::TARGET_SU_CTX,
+            Uid::from_raw(0),
+            Gid::from_raw(0),
+            move || {
+                let maint_service = get_keystore_maintenance_service();
+                // Clear state left behind by a previous run.
+                maint_service.onUserRemoved(user_id).expect("Failed to remove user.");
+                maint_service.onUserAdded(user_id).expect("Failed to add user.");
+            },
+        )
+    };
+
+    // SAFETY: The caller guarantees that there are no other threads.
+    let aliases = unsafe {
+        run_as::run_as(TARGET_CTX, Uid::from_raw(uid), Gid::from_raw(uid), move || {
+            let keystore2 = get_keystore_service();
+            let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+            (0..KEY_COUNT)
+                .map(|i| {
+                    let alias = test_alias(&format!("assert_user_removal_deletes_keys_{}", i));
+                    key_generations::generate_ec_p256_signing_key(
+                        &sec_level,
+                        Domain::APP,
+                        -1,
+                        Some(alias.clone()),
+                        None,
+                    )
+                    .unwrap();
+                    alias
+                })
+                .collect::<Vec<_>>()
+        })
+    };
+
+    // SAFETY: The caller guarantees that there are no other threads.
+    unsafe { remove_user(user_id) };
+
+    // SAFETY: The caller guarantees that there are no other threads.
+    unsafe {
+        run_as::run_as(TARGET_CTX, Uid::from_raw(uid), Gid::from_raw(uid), move || {
+            let keystore2 = get_keystore_service();
+            let listed: Vec<String> = keystore2
+                .listEntries(Domain::APP, -1)
+                .unwrap()
+                .into_iter()
+                .filter_map(|key| key.alias)
+                .collect();
+            aliases
+                .into_iter()
+                .filter(|alias| {
+                    let key = KeyDescriptor {
+                        domain: Domain::APP,
+                        nspace: -1,
+                        alias: Some(alias.clone()),
+                        blob: None,
+                    };
+                    listed.contains(alias) || keystore2.getKeyEntry(&key).is_ok()
+                })
+                .collect()
+        })
+    }
+}
+
+/// Generate keys as an app in a freshly added user and remove that user. Test should find none
+/// of the generated keys after the user was removed.
+#[test]
+fn keystore2_user_removal_deletes_keys() {
+    // SAFETY: The test is run in a separate process with no other threads.
+    let survivors = unsafe { assert_user_removal_deletes_keys(95) };
+    assert!(survivors.is_empty(), "Keys survived user removal: {:?}", survivors);
+}
+
+/// Generate keys as an app and clear the app's namespace using the maintenance `clearNamespace`
+/// API with `su` context. Test should find no keys left in the app's namespace afterwards.
+#[test]
+fn keystore2_clear_app_namespace_deletes_keys() {
+    const USER_ID: u32 = 94;
+    let uid = USER_ID * AID_USER_OFFSET + 10001;
+
+    // SAFETY: The test is run in a separate process with no other threads.
+    unsafe {
+        run_as::run_as(TARGET_CTX, Uid::from_raw(uid), Gid::from_raw(uid), move || {
+            let keystore2 = get_keystore_service();
+            let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+            for i in 0..3 {
+                key_generations::generate_ec_p256_signing_key(
+                    &sec_level,
+                    Domain::APP,
+                    -1,
+                    Some(test_alias(&format!("keystore2_clear_app_namespace_deletes_keys_{}", i))),
+                    None,
+                )
+                .unwrap();
+            }
+            assert_eq!(3, keystore2.getNumberOfEntries(Domain::APP, -1).unwrap());
+        })
+    };
+
+    // SAFETY: The test is run in a separate process with no other threads.
+    unsafe {
+        run_as::run_as(
+            key_generations::TARGET_SU_CTX,
+            Uid::from_raw(0),
+            Gid::from_raw(0),
+            move || {
+                clear_namespace(Domain::APP, uid.into()).unwrap();
+            },
+        )
+    };
+
+    // SAFETY: The test is run in a separate process with no other threads.
+    unsafe {
+        run_as::run_as(TARGET_CTX, Uid::from_raw(uid), Gid::from_raw(uid), move || {
+            assert_namespace_cleared(&get_keystore_service(), Domain::APP, -1);
+        })
+    };
+}
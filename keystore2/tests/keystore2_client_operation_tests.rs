@@ -12,17 +12,24 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use nix::unistd::{getuid, Gid, Uid};
+use binder::Interface;
+use nix::unistd::{Gid, Uid};
+use openssl::hash::MessageDigest;
+use openssl::sign::Verifier;
+use openssl::x509::X509;
 use rustutils::users::AID_USER_OFFSET;
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
-    Digest::Digest, ErrorCode::ErrorCode, KeyPurpose::KeyPurpose, SecurityLevel::SecurityLevel,
+    Algorithm::Algorithm, Digest::Digest, EcCurve::EcCurve, ErrorCode::ErrorCode,
+    KeyPurpose::KeyPurpose, PaddingMode::PaddingMode, SecurityLevel::SecurityLevel,
 };
 use android_system_keystore2::aidl::android::system::keystore2::{
     CreateOperationResponse::CreateOperationResponse, Domain::Domain,
-    IKeystoreOperation::IKeystoreOperation, ResponseCode::ResponseCode,
+    IKeystoreOperation::IKeystoreOperation, IKeystoreSecurityLevel::IKeystoreSecurityLevel,
+    KeyDescriptor::KeyDescriptor, ResponseCode::ResponseCode,
 };
 
 use keystore2_test_utils::{
@@ -30,8 +37,10 @@ use keystore2_test_utils::{
 };
 
 use crate::keystore2_client_test_utils::{
-    create_signing_operation, execute_op_run_as_child, perform_sample_sign_operation,
-    BarrierReached, ForcedOp, TestOutcome,
+    abort_operation, create_signing_operation, delete_app_key, execute_op_run_as_child,
+    get_keystore_metrics, get_op_digest, log_test_skip, perform_sample_sign_operation,
+    run_conformance_matrix, test_alias, BarrierReached, ForcedOp, KeystoreMetrics, OperationCall,
+    OperationTranscript, TestOutcome,
 };
 
 /// Create `max_ops` number child processes with the given context and perform an operation under each
@@ -45,9 +54,26 @@ pub unsafe fn create_operations(
     forced_op: ForcedOp,
     max_ops: i32,
 ) -> Vec<run_as::ChildHandle<TestOutcome, BarrierReached>> {
-    let alias = format!("ks_op_test_key_{}", getuid());
-    let base_gid = 99 * AID_USER_OFFSET + 10001;
-    let base_uid = 99 * AID_USER_OFFSET + 10001;
+    // SAFETY: The caller guarantees that there are no other threads.
+    unsafe { create_operations_with_base_app_id(target_ctx, forced_op, max_ops, 10001) }
+}
+
+/// Same as `create_operations`, but child processes are assigned consecutive UIDs starting at
+/// `base_app_id` in user 99. This allows multiple groups of operations to be outstanding at
+/// the same time without their owners overlapping.
+///
+/// # Safety
+///
+/// Must be called from a process with no other threads.
+pub unsafe fn create_operations_with_base_app_id(
+    target_ctx: &'static str,
+    forced_op: ForcedOp,
+    max_ops: i32,
+    base_app_id: u32,
+) -> Vec<run_as::ChildHandle<TestOutcome, BarrierReached>> {
+    let alias = test_alias("create_operations_with_base_app_id");
+    let base_gid = 99 * AID_USER_OFFSET + base_app_id;
+    let base_uid = 99 * AID_USER_OFFSET + base_app_id;
     (0..max_ops)
         // SAFETY: The caller guarantees that there are no other threads.
         .map(|i| unsafe {
@@ -64,6 +90,184 @@ pub unsafe fn create_operations(
         .collect()
 }
 
+/// Create a forced operation in a child process with `su` context and wait until the child
+/// notifies that the operation has been created. The child keeps the operation open until it is
+/// notified to continue.
+///
+/// # Safety
+///
+/// Must be called from a process with no other threads.
+unsafe fn start_forced_op_and_wait(
+    app_id: u32,
+    alias: String,
+) -> run_as::ChildHandle<TestOutcome, BarrierReached> {
+    let auid = 99 * AID_USER_OFFSET + app_id;
+    let agid = 99 * AID_USER_OFFSET + app_id;
+    // SAFETY: The caller guarantees that there are no other threads.
+    let mut child_handle = unsafe {
+        execute_op_run_as_child(
+            key_generations::TARGET_SU_CTX,
+            Domain::SELINUX,
+            key_generations::SELINUX_SHELL_NAMESPACE,
+            Some(alias),
+            Uid::from_raw(auid),
+            Gid::from_raw(agid),
+            ForcedOp(true),
+        )
+    };
+    child_handle.recv();
+    child_handle
+}
+
+/// Wait until all child procs notify us that their operations have been created.
+fn wait_for_child_ops(child_handles: &mut [run_as::ChildHandle<TestOutcome, BarrierReached>]) {
+    for ch in child_handles.iter_mut() {
+        ch.recv();
+    }
+}
+
+/// Notify each child to resume and finish, then collect the outcomes in order.
+fn resume_and_collect_child_ops(
+    mut child_handles: Vec<run_as::ChildHandle<TestOutcome, BarrierReached>>,
+) -> Vec<TestOutcome> {
+    for ch in child_handles.iter_mut() {
+        ch.send(&BarrierReached {});
+    }
+    child_handles.into_iter().map(|ch| ch.get_result()).collect()
+}
+
+/// Count the outcomes matching `expected`.
+fn count_outcomes(outcomes: &[TestOutcome], expected: TestOutcome) -> usize {
+    outcomes.iter().filter(|o| **o == expected).count()
+}
+
+/// Summary of which categories of operations were affected by pruning.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PruningSummary {
+    /// Regular operations which were created and later pruned.
+    pub regular_pruned: usize,
+    /// Regular operations which failed to be created with `BACKEND_BUSY`.
+    pub regular_busy: usize,
+    /// Forced operations which were created and later pruned.
+    pub forced_pruned: usize,
+    /// Forced operations which failed to be created with `BACKEND_BUSY`.
+    pub forced_busy: usize,
+    /// Outcome of the forced operation created before all other operations.
+    pub first_forced_op: TestOutcome,
+}
+
+/// Fills the operation slots with regular operations, then adds forced operations on top and
+/// confirms that regular operations are pruned to make room while no forced operation gets
+/// evicted.
+///  1. Create an initial forced operation and keep it open.
+///  2. Create `MAX_OPS` regular operations, enough to trigger BACKEND_BUSY.
+///  3. Create `MAX_OPS` forced operations. These can only get slots by pruning regular ones.
+///  4. Complete all operations and classify the outcomes.
+///
+/// # Safety
+///
+/// Must be called from a process with no other threads.
+pub unsafe fn assert_forced_prunes_regular_before_forced() -> PruningSummary {
+    const MAX_OPS: i32 = 100;
+    static TARGET_CTX: &str = "u:r:untrusted_app:s0:c91,c256,c10,c20";
+
+    let alias = test_alias("assert_forced_prunes_regular_before_forced");
+    // SAFETY: The caller guarantees that there are no other threads.
+    let first_op_handle = unsafe { start_forced_op_and_wait(10206, alias) };
+
+    // SAFETY: The caller guarantees that there are no other threads.
+    let mut regular_handles = unsafe { create_operations(TARGET_CTX, ForcedOp(false), MAX_OPS) };
+    wait_for_child_ops(&mut regular_handles);
+
+    // SAFETY: The caller guarantees that there are no other threads.
+    let mut forced_handles = unsafe {
+        create_operations_with_base_app_id(
+            key_generations::TARGET_SU_CTX,
+            ForcedOp(true),
+            MAX_OPS,
+            10301,
+        )
+    };
+    wait_for_child_ops(&mut forced_handles);
+
+    let first_forced_op = resume_and_collect_child_ops(vec![first_op_handle]).remove(0);
+    let forced = resume_and_collect_child_ops(forced_handles);
+    let regular = resume_and_collect_child_ops(regular_handles);
+
+    let summary = PruningSummary {
+        regular_pruned: count_outcomes(&regular, TestOutcome::InvalidHandle),
+        regular_busy: count_outcomes(&regular, TestOutcome::BackendBusy),
+        forced_pruned: count_outcomes(&forced, TestOutcome::InvalidHandle),
+        forced_busy: count_outcomes(&forced, TestOutcome::BackendBusy),
+        first_forced_op,
+    };
+
+    assert_eq!(summary.first_forced_op, TestOutcome::Ok, "{:?}", summary);
+    assert!(summary.regular_pruned > 0, "No regular operation was pruned: {:?}", summary);
+    assert_eq!(summary.forced_pruned, 0, "A forced operation was pruned: {:?}", summary);
+    summary
+}
+
+/// Outcome of forced operations competing with each other for the operation slots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForcedPriorityReport {
+    /// Forced operations occupying the slots first which were later pruned, i.e. the victims.
+    pub early_pruned: usize,
+    /// Forced operations created afterwards which were later pruned.
+    pub late_pruned: usize,
+    /// Forced operations created afterwards which failed with `BACKEND_BUSY`.
+    pub late_busy: usize,
+}
+
+/// Fills the operation slots with forced operations and then requests more forced operations
+/// from a different group of owners. Keystore doesn't rank forced operations against each other,
+/// regardless of the context they come from: a forced operation is never a pruning candidate.
+/// So rather than pruning a victim the late forced operations are expected to fail with
+/// `BACKEND_BUSY` while all early forced operations survive.
+///
+/// # Safety
+///
+/// Must be called from a process with no other threads.
+pub unsafe fn assert_forced_op_priority_among_forced() -> ForcedPriorityReport {
+    const MAX_OPS: i32 = 100;
+
+    // SAFETY: The caller guarantees that there are no other threads.
+    let mut early_handles = unsafe {
+        create_operations_with_base_app_id(
+            key_generations::TARGET_SU_CTX,
+            ForcedOp(true),
+            MAX_OPS,
+            10401,
+        )
+    };
+    wait_for_child_ops(&mut early_handles);
+
+    // SAFETY: The caller guarantees that there are no other threads.
+    let mut late_handles = unsafe {
+        create_operations_with_base_app_id(
+            key_generations::TARGET_SU_CTX,
+            ForcedOp(true),
+            MAX_OPS,
+            10501,
+        )
+    };
+    wait_for_child_ops(&mut late_handles);
+
+    let early = resume_and_collect_child_ops(early_handles);
+    let late = resume_and_collect_child_ops(late_handles);
+
+    let report = ForcedPriorityReport {
+        early_pruned: count_outcomes(&early, TestOutcome::InvalidHandle),
+        late_pruned: count_outcomes(&late, TestOutcome::InvalidHandle),
+        late_busy: count_outcomes(&late, TestOutcome::BackendBusy),
+    };
+
+    assert_eq!(report.early_pruned, 0, "A forced operation was pruned: {:?}", report);
+    assert_eq!(report.late_pruned, 0, "A forced operation was pruned: {:?}", report);
+    assert!(report.late_busy > 0, "Late forced operations were not refused: {:?}", report);
+    report
+}
+
 /// Executes an operation in a thread. Expect an `OPERATION_BUSY` error in case of operation
 /// failure. Returns True if `OPERATION_BUSY` error is encountered otherwise returns false.
 fn perform_op_busy_in_thread(op: binder::Strong<dyn IKeystoreOperation>) -> JoinHandle<bool> {
@@ -145,7 +349,7 @@ fn keystore2_forced_op_after_backendbusy_test() {
             Uid::from_raw(auid),
             Gid::from_raw(agid),
             move || {
-                let alias = format!("ks_prune_forced_op_key_{}", getuid());
+                let alias = test_alias("keystore2_forced_op_after_backendbusy_test");
 
                 // To make room for this forced op, system should be able to prune one of the
                 // above created regular operations and create a slot for this forced operation
@@ -205,28 +409,12 @@ fn keystore2_forced_op_after_backendbusy_test() {
 #[test]
 fn keystore2_max_forced_ops_test() {
     const MAX_OPS: i32 = 100;
-    let auid = 99 * AID_USER_OFFSET + 10205;
-    let agid = 99 * AID_USER_OFFSET + 10205;
 
     // Create initial forced operation in a child process
     // and wait for the parent to notify to perform operation.
-    let alias = format!("ks_forced_op_key_{}", getuid());
+    let alias = test_alias("keystore2_max_forced_ops_test");
     // SAFETY: The test is run in a separate process with no other threads.
-    let mut first_op_handle = unsafe {
-        execute_op_run_as_child(
-            key_generations::TARGET_SU_CTX,
-            Domain::SELINUX,
-            key_generations::SELINUX_SHELL_NAMESPACE,
-            Some(alias),
-            Uid::from_raw(auid),
-            Gid::from_raw(agid),
-            ForcedOp(true),
-        )
-    };
-
-    // Wait until above child proc notifies us to continue, so that there is definitely a forced
-    // operation outstanding to perform a operation.
-    first_op_handle.recv();
+    let first_op_handle = unsafe { start_forced_op_and_wait(10205, alias) };
 
     // Create MAX_OPS number of forced operations.
     let mut child_handles =
@@ -235,31 +423,75 @@ fn keystore2_max_forced_ops_test() {
 
     // Wait until all child procs notifies us to continue, so that  there are enough operations
     // outstanding to trigger a BACKEND_BUSY.
-    for ch in child_handles.iter_mut() {
-        ch.recv();
-    }
+    wait_for_child_ops(&mut child_handles);
 
     // Notify initial created forced operation to continue performing the operations.
-    first_op_handle.send(&BarrierReached {});
-
     // Collect initially created forced operation result and is expected to complete operation
     // successfully.
-    let first_op_result = first_op_handle.get_result();
-    assert_eq!(first_op_result, TestOutcome::Ok);
-
-    // Notify each child to resume and finish.
-    for ch in child_handles.iter_mut() {
-        ch.send(&BarrierReached {});
-    }
+    let first_op_result = resume_and_collect_child_ops(vec![first_op_handle]);
+    assert_eq!(first_op_result, vec![TestOutcome::Ok]);
 
     // Collect the result and validate whether backend busy has occurred with MAX_OPS number
     // of forced operations.
-    let busy_count = child_handles
-        .into_iter()
-        .map(|ch| ch.get_result())
-        .filter(|r| *r == TestOutcome::BackendBusy)
-        .count();
-    assert!(busy_count > 0);
+    let results = resume_and_collect_child_ops(child_handles);
+    assert!(count_outcomes(&results, TestOutcome::BackendBusy) > 0);
+}
+
+/// This test confirms that regular operations are pruned before any forced operation is evicted
+/// when regular and forced operations compete for the operation slots.
+#[test]
+fn keystore2_forced_prunes_regular_before_forced_test() {
+    // SAFETY: The test is run in a separate process with no other threads.
+    let summary = unsafe { assert_forced_prunes_regular_before_forced() };
+    assert!(summary.regular_busy > 0 || summary.forced_busy > 0);
+}
+
+/// This test confirms that once all operation slots are taken by forced operations, further
+/// forced operations cannot prune any of them and fail with `BACKEND_BUSY` instead.
+#[test]
+fn keystore2_forced_op_priority_among_forced_test() {
+    // SAFETY: The test is run in a separate process with no other threads.
+    let report = unsafe { assert_forced_op_priority_among_forced() };
+    assert_eq!(report.early_pruned + report.late_pruned, 0);
+}
+
+/// Opens a forced operation, then fills the remaining slots with regular operations owned by
+/// other apps and then tries to complete the forced operation. Returns the outcome of the forced
+/// operation along with the outcomes of the regular operations.
+///
+/// # Safety
+///
+/// Must be called from a process with no other threads.
+pub unsafe fn assert_regular_cannot_prune_forced() -> (TestOutcome, Vec<TestOutcome>) {
+    const MAX_OPS: i32 = 100;
+    static TARGET_CTX: &str = "u:r:untrusted_app:s0:c91,c256,c10,c20";
+
+    let alias = test_alias("assert_regular_cannot_prune_forced");
+    // SAFETY: The caller guarantees that there are no other threads.
+    let forced_op_handle = unsafe { start_forced_op_and_wait(10207, alias) };
+
+    // SAFETY: The caller guarantees that there are no other threads.
+    let mut regular_handles =
+        unsafe { create_operations_with_base_app_id(TARGET_CTX, ForcedOp(false), MAX_OPS, 10701) };
+    wait_for_child_ops(&mut regular_handles);
+
+    let forced_op = resume_and_collect_child_ops(vec![forced_op_handle]).remove(0);
+    let regular = resume_and_collect_child_ops(regular_handles);
+    (forced_op, regular)
+}
+
+/// This test confirms that regular operations can't prune a forced operation, even when they
+/// exhaust all remaining operation slots.
+#[test]
+fn keystore2_regular_ops_cannot_prune_forced_test() {
+    // SAFETY: The test is run in a separate process with no other threads.
+    let (forced_op, regular) = unsafe { assert_regular_cannot_prune_forced() };
+    assert_eq!(forced_op, TestOutcome::Ok, "The forced operation was pruned by regular ones.");
+    assert!(
+        count_outcomes(&regular, TestOutcome::BackendBusy)
+            + count_outcomes(&regular, TestOutcome::InvalidHandle)
+            > 0
+    );
 }
 
 /// This test will verify the use case with the same owner(UID) requesting `n` number of operations.
@@ -293,7 +525,7 @@ fn keystore2_ops_prune_test() {
 
     // Create an operation in an untrusted_app context. Wait until the parent notifies to continue.
     // Once the parent notifies, this operation is expected to be completed successfully.
-    let alias = format!("ks_reg_op_key_{}", getuid());
+    let alias = test_alias("keystore2_ops_prune_test_child");
     // SAFETY: The test is run in a separate process with no other threads.
     let mut child_handle = unsafe {
         execute_op_run_as_child(
@@ -314,7 +546,7 @@ fn keystore2_ops_prune_test() {
     // Generate a key to use in below operations.
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
-    let alias = format!("ks_prune_op_test_key_{}", getuid());
+    let alias = test_alias("keystore2_ops_prune_test");
     let key_metadata = key_generations::generate_ec_p256_signing_key(
         &sec_level,
         Domain::SELINUX,
@@ -376,6 +608,335 @@ fn keystore2_ops_prune_test() {
     }
 }
 
+/// Create forced operations with the given key until the backend fails with `BACKEND_BUSY`.
+/// Forced operations can't prune other forced operations, so this fills every free operation
+/// slot. Panics if `BACKEND_BUSY` isn't reached within a generous limit. Returns the created
+/// operations, which the caller must abort.
+fn fill_op_slots_with_forced_ops(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    key: &KeyDescriptor,
+) -> Vec<binder::Strong<dyn IKeystoreOperation>> {
+    const MAX_OPS_LIMIT: usize = 1000;
+    let op_params =
+        authorizations::AuthSetBuilder::new().purpose(KeyPurpose::SIGN).digest(Digest::SHA_2_256);
+
+    let mut ops = Vec::new();
+    while ops.len() < MAX_OPS_LIMIT {
+        match key_generations::map_ks_error(sec_level.createOperation(key, &op_params, true)) {
+            Ok(CreateOperationResponse { iOperation: Some(op), .. }) => ops.push(op),
+            Err(Error::Rc(ResponseCode::BACKEND_BUSY)) => return ops,
+            Ok(_) => panic!("Operation should have created successfully."),
+            Err(e) => panic!("Unexpected error while creating a forced operation: {:?}", e),
+        }
+    }
+    panic!("No BACKEND_BUSY after {} forced operations.", MAX_OPS_LIMIT);
+}
+
+/// Fill every operation slot with forced operations, release one of them with `release` and
+/// check that its handle is no longer usable. Then create a regular operation, which can't prune
+/// any of the forced operations and thus only succeeds in the released slot. Creation is retried
+/// up to `max_retries` times on `BACKEND_BUSY`. All operations are aborted afterwards. Returns
+/// the number of retries needed, or `None` if no operation could be created.
+fn assert_released_op_frees_slot(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    alias: String,
+    max_retries: u32,
+    release: impl FnOnce(&binder::Strong<dyn IKeystoreOperation>),
+) -> Option<u32> {
+    let key_metadata = key_generations::generate_ec_p256_signing_key(
+        sec_level,
+        Domain::SELINUX,
+        key_generations::SELINUX_SHELL_NAMESPACE,
+        Some(alias),
+        None,
+    )
+    .unwrap();
+    let op_params =
+        authorizations::AuthSetBuilder::new().purpose(KeyPurpose::SIGN).digest(Digest::SHA_2_256);
+
+    let mut forced_ops = fill_op_slots_with_forced_ops(sec_level, &key_metadata.key);
+    let released_op = forced_ops.pop().expect("No free operation slot.");
+    release(&released_op);
+    assert_eq!(
+        Err(Error::Km(ErrorCode::INVALID_OPERATION_HANDLE)),
+        key_generations::map_ks_error(released_op.update(b"my message"))
+    );
+
+    let mut retries = None;
+    for attempt in 0..=max_retries {
+        match key_generations::map_ks_error(sec_level.createOperation(
+            &key_metadata.key,
+            &op_params,
+            false,
+        )) {
+            Ok(CreateOperationResponse { iOperation: Some(op), .. }) => {
+                assert_eq!(
+                    Ok(()),
+                    key_generations::map_ks_error(perform_sample_sign_operation(&op))
+                );
+                retries = Some(attempt);
+                break;
+            }
+            Err(Error::Rc(ResponseCode::BACKEND_BUSY)) => {
+                thread::sleep(Duration::from_millis(100));
+            }
+            Ok(_) => panic!("Operation should have created successfully."),
+            Err(e) => panic!("Unexpected error while creating an operation: {:?}", e),
+        }
+    }
+
+    for op in &forced_ops {
+        assert_eq!(Ok(()), key_generations::map_ks_error(abort_operation(op)));
+    }
+    retries
+}
+
+/// Fill every operation slot and abort one of the operations. The aborted operation handle must
+/// no longer be usable and its slot must be immediately reusable. Returns whether creating a
+/// new operation succeeded on the first try, i.e. without encountering `BACKEND_BUSY`.
+fn assert_aborted_op_frees_slot(sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>) -> bool {
+    let alias = test_alias("assert_aborted_op_frees_slot");
+    let retries = assert_released_op_frees_slot(sec_level, alias, 0, |op| {
+        assert_eq!(Ok(()), key_generations::map_ks_error(abort_operation(op)));
+    });
+    retries == Some(0)
+}
+
+/// Fill every operation slot and finish one of the operations. The finished operation handle must
+/// no longer be usable and its slot must be reusable. Returns whether creating a new operation
+/// succeeded on the first try; otherwise it is retried a few times on `BACKEND_BUSY` and must
+/// succeed eventually.
+fn assert_finish_frees_slot(sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>) -> bool {
+    const MAX_RETRIES: u32 = 5;
+    let alias = test_alias("assert_finish_frees_slot");
+    let retries = assert_released_op_frees_slot(sec_level, alias, MAX_RETRIES, |op| {
+        assert_eq!(Ok(()), key_generations::map_ks_error(perform_sample_sign_operation(op)));
+    });
+    match retries {
+        Some(retries) => retries == 0,
+        None => {
+            panic!("Failed to create an operation after finishing one in {} retries.", MAX_RETRIES)
+        }
+    }
+}
+
+/// Create an operation, leave it idle for `idle` and then try to use it. Returns true if the
+/// operation was pruned in the meantime, i.e. using it failed with `INVALID_OPERATION_HANDLE`,
+/// and false if the operation survived and could be completed.
+fn assert_idle_op_pruned_after(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    idle: Duration,
+) -> bool {
+    let alias = test_alias("assert_idle_op_pruned_after");
+    let key_metadata = key_generations::generate_ec_p256_signing_key(
+        sec_level,
+        Domain::SELINUX,
+        key_generations::SELINUX_SHELL_NAMESPACE,
+        Some(alias),
+        None,
+    )
+    .unwrap();
+
+    let op = sec_level
+        .createOperation(
+            &key_metadata.key,
+            &authorizations::AuthSetBuilder::new()
+                .purpose(KeyPurpose::SIGN)
+                .digest(Digest::SHA_2_256),
+            false,
+        )
+        .unwrap()
+        .iOperation
+        .expect("Operation should have created successfully.");
+
+    thread::sleep(idle);
+
+    match key_generations::map_ks_error(perform_sample_sign_operation(&op)) {
+        Ok(()) => false,
+        Err(Error::Km(ErrorCode::INVALID_OPERATION_HANDLE)) => true,
+        Err(e) => panic!("Unexpected error while using an idle operation: {:?}", e),
+    }
+}
+
+/// Keystore only prunes operations when it runs out of operation slots, there is no idle
+/// timeout. An operation left idle without any competing operations should survive.
+#[test]
+fn keystore2_idle_op_not_pruned_test() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    assert!(
+        !assert_idle_op_pruned_after(&sec_level, Duration::from_secs(5)),
+        "Idle operation was pruned."
+    );
+}
+
+/// Generate a signing key of the given algorithm and sign a sample message with it `iterations`
+/// times, creating a new operation for each signature. Only the signing is timed, key generation
+/// is excluded. Returns the number of signatures per second.
+/// Supported algorithms are `EC` (P-256), `RSA` (2048 bit, PKCS#1 v1.5) and `HMAC`, all with
+/// `SHA_2_256` digest.
+pub fn bench_sign_throughput(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    algorithm: Algorithm,
+    iterations: u32,
+) -> f64 {
+    let alias = test_alias(&format!("bench_sign_throughput_{}", algorithm.0));
+    let mut op_params =
+        authorizations::AuthSetBuilder::new().purpose(KeyPurpose::SIGN).digest(Digest::SHA_2_256);
+    let key_metadata = match algorithm {
+        Algorithm::EC => key_generations::generate_ec_key(
+            sec_level,
+            Domain::APP,
+            -1,
+            Some(alias.clone()),
+            EcCurve::P_256,
+            Digest::SHA_2_256,
+        ),
+        Algorithm::RSA => {
+            op_params = op_params.padding_mode(PaddingMode::RSA_PKCS1_1_5_SIGN);
+            key_generations::generate_rsa_key(
+                sec_level,
+                Domain::APP,
+                -1,
+                Some(alias.clone()),
+                &key_generations::KeyParams {
+                    key_size: 2048,
+                    purpose: vec![KeyPurpose::SIGN, KeyPurpose::VERIFY],
+                    padding: Some(PaddingMode::RSA_PKCS1_1_5_SIGN),
+                    digest: Some(Digest::SHA_2_256),
+                    mgf_digest: None,
+                    block_mode: None,
+                    att_challenge: None,
+                },
+                None,
+            )
+        }
+        Algorithm::HMAC => {
+            op_params = op_params.mac_length(256);
+            key_generations::generate_hmac_key(sec_level, &alias, 256, 256, Digest::SHA_2_256)
+        }
+        _ => panic!("Unsupported signing algorithm {:?}", algorithm),
+    }
+    .unwrap();
+
+    let start = Instant::now();
+    for _ in 0..iterations {
+        let op = sec_level
+            .createOperation(&key_metadata.key, &op_params, false)
+            .unwrap()
+            .iOperation
+            .expect("Operation should have created successfully.");
+        perform_sample_sign_operation(&op).unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    delete_app_key(&get_keystore_service(), &alias).unwrap();
+    f64::from(iterations) / elapsed.as_secs_f64()
+}
+
+/// Measure the signing throughput of EC, RSA and HMAC keys and print the results.
+#[test]
+fn keystore2_sign_throughput_test() {
+    const ITERATIONS: u32 = 20;
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    for algorithm in [Algorithm::EC, Algorithm::RSA, Algorithm::HMAC] {
+        let rate = bench_sign_throughput(&sec_level, algorithm, ITERATIONS);
+        assert!(rate > 0.0);
+        println!("{:?}: {:.1} signatures/s", algorithm, rate);
+    }
+}
+
+/// Fill every operation slot with forced operations and abort one of them. The slot of the
+/// aborted operation should be reusable right away, so that a new operation can be created on the first try.
+#[test]
+fn keystore2_abort_op_frees_slot_test() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+    assert!(
+        assert_aborted_op_frees_slot(&sec_level),
+        "Creating an operation after aborting one failed with BACKEND_BUSY."
+    );
+}
+
+/// Fill every operation slot with forced operations and finish one of them. The slot of the
+/// finished operation should be reusable right away, so that a new operation can be created without retrying.
+#[test]
+fn keystore2_finish_op_frees_slot_test() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+    assert!(
+        assert_finish_frees_slot(&sec_level),
+        "Creating an operation after finishing one required a retry."
+    );
+}
+
+/// Try to create a forced operation from the given SELinux context, which must lack the
+/// `req_forced_op` permission. Creating the operation is expected to fail with
+/// `PERMISSION_DENIED`, whereas the same context is able to create regular operations.
+///
+/// # Safety
+///
+/// Must only be called from a single-threaded process.
+pub unsafe fn assert_forced_op_requires_permission(ctx_without_perm: &str) {
+    const USER_ID: u32 = 99;
+    const APPLICATION_ID: u32 = 10601;
+
+    let uid = USER_ID * AID_USER_OFFSET + APPLICATION_ID;
+    let gid = USER_ID * AID_USER_OFFSET + APPLICATION_ID;
+
+    // SAFETY: The caller guarantees that there are no other threads.
+    unsafe {
+        run_as::run_as(ctx_without_perm, Uid::from_raw(uid), Gid::from_raw(gid), move || {
+            let alias = test_alias("assert_forced_op_requires_permission");
+            let result = key_generations::map_ks_error(create_signing_operation(
+                ForcedOp(true),
+                KeyPurpose::SIGN,
+                Digest::SHA_2_256,
+                Domain::APP,
+                -1,
+                Some(alias.clone()),
+            ));
+            assert!(result.is_err());
+            assert_eq!(Error::Rc(ResponseCode::PERMISSION_DENIED), result.unwrap_err());
+
+            // The denial must be specific to forced operations.
+            create_signing_operation(
+                ForcedOp(false),
+                KeyPurpose::SIGN,
+                Digest::SHA_2_256,
+                Domain::APP,
+                -1,
+                Some(alias),
+            )
+            .expect("Failed to create a regular operation.");
+        });
+    }
+}
+
+/// Try to create a forced operation from the `untrusted_app` context, which lacks the
+/// `req_forced_op` permission, and expect it to fail with `PERMISSION_DENIED`. The operation is
+/// created by a child process running as `untrusted_app`, which has to obtain its own security
+/// level, so this takes none.
+///
+/// # Safety
+///
+/// Must only be called from a single-threaded process.
+pub unsafe fn assert_unprivileged_forced_op_denied() {
+    // SAFETY: The caller guarantees that there are no other threads.
+    unsafe { assert_forced_op_requires_permission("u:r:untrusted_app:s0") };
+}
+
+/// Try to create a forced operation from the `untrusted_app` context. Test should fail to create
+/// the operation with `PERMISSION_DENIED`.
+#[test]
+fn keystore2_unprivileged_forced_op_denied_test() {
+    // SAFETY: The test is run in a separate process with no other threads.
+    unsafe { assert_unprivileged_forced_op_denied() };
+}
+
 /// Try to create forced operations with various contexts -
 ///   - untrusted_app
 ///   - system_server
@@ -385,29 +946,10 @@ fn keystore2_ops_prune_test() {
 fn keystore2_forced_op_perm_denied_test() {
     static TARGET_CTXS: &[&str] =
         &["u:r:untrusted_app:s0", "u:r:system_server:s0", "u:r:priv_app:s0"];
-    const USER_ID: u32 = 99;
-    const APPLICATION_ID: u32 = 10601;
-
-    let uid = USER_ID * AID_USER_OFFSET + APPLICATION_ID;
-    let gid = USER_ID * AID_USER_OFFSET + APPLICATION_ID;
 
     for context in TARGET_CTXS.iter() {
         // SAFETY: The test is run in a separate process with no other threads.
-        unsafe {
-            run_as::run_as(context, Uid::from_raw(uid), Gid::from_raw(gid), move || {
-                let alias = format!("ks_app_forced_op_test_key_{}", getuid());
-                let result = key_generations::map_ks_error(create_signing_operation(
-                    ForcedOp(true),
-                    KeyPurpose::SIGN,
-                    Digest::SHA_2_256,
-                    Domain::APP,
-                    -1,
-                    Some(alias),
-                ));
-                assert!(result.is_err());
-                assert_eq!(Error::Rc(ResponseCode::PERMISSION_DENIED), result.unwrap_err());
-            });
-        }
+        unsafe { assert_forced_op_requires_permission(context) };
     }
 }
 
@@ -425,7 +967,7 @@ fn keystore2_forced_op_success_test() {
     // SAFETY: The test is run in a separate process with no other threads.
     unsafe {
         run_as::run_as(TARGET_CTX, Uid::from_raw(uid), Gid::from_raw(gid), move || {
-            let alias = format!("ks_vold_forced_op_key_{}", getuid());
+            let alias = test_alias("keystore2_forced_op_success_test");
             create_signing_operation(
                 ForcedOp(true),
                 KeyPurpose::SIGN,
@@ -464,3 +1006,927 @@ fn keystore2_op_fails_operation_busy() {
 
     assert!(result1 || result2);
 }
+
+/// Generate an EC key with the given alias and perform a sample sign operation with it. Returns the
+/// outcome, classifying any failure instead of panicking.
+fn classify_sample_sign_op(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    alias: &str,
+) -> TestOutcome {
+    let Ok(key_metadata) = key_generations::generate_ec_p256_signing_key(
+        sec_level,
+        Domain::APP,
+        -1,
+        Some(alias.to_string()),
+        None,
+    ) else {
+        return TestOutcome::OtherErr;
+    };
+
+    let result = key_generations::map_ks_error(sec_level.createOperation(
+        &key_metadata.key,
+        &authorizations::AuthSetBuilder::new().purpose(KeyPurpose::SIGN).digest(Digest::SHA_2_256),
+        false,
+    ));
+    match result {
+        Ok(CreateOperationResponse { iOperation: Some(op), .. }) => {
+            match key_generations::map_ks_error(perform_sample_sign_operation(&op)) {
+                Ok(()) => TestOutcome::Ok,
+                Err(Error::Km(ErrorCode::INVALID_OPERATION_HANDLE)) => TestOutcome::InvalidHandle,
+                Err(_) => TestOutcome::OtherErr,
+            }
+        }
+        Err(Error::Rc(ResponseCode::BACKEND_BUSY)) => TestOutcome::BackendBusy,
+        _ => TestOutcome::OtherErr,
+    }
+}
+
+/// In a child process allocate and touch `alloc_mb` MiB of memory, then generate a key with the
+/// given security level and perform a sample sign operation with it while holding on to the
+/// allocation. Returns whether the allocation succeeded along with the classified outcome of the
+/// operation. A failing allocation is reported instead of aborting the child.
+///
+/// # Safety
+///
+/// Must be called from a process with no other threads.
+pub unsafe fn create_operation_under_memory_pressure(
+    sec_level: SecurityLevel,
+    alloc_mb: usize,
+) -> (bool, TestOutcome) {
+    const APPLICATION_ID: u32 = 10601;
+    let uid = 99 * AID_USER_OFFSET + APPLICATION_ID;
+    let gid = 99 * AID_USER_OFFSET + APPLICATION_ID;
+
+    // SAFETY: The caller guarantees that there are no other threads.
+    unsafe {
+        run_as::run_as(
+            "u:r:untrusted_app:s0:c91,c256,c10,c20",
+            Uid::from_raw(uid),
+            Gid::from_raw(gid),
+            move || {
+                let alloc_len = alloc_mb << 20;
+                let mut ballast: Vec<u8> = Vec::new();
+                let allocated = ballast.try_reserve_exact(alloc_len).is_ok();
+                if allocated {
+                    // Touch every page so that the allocation is actually backed by memory.
+                    ballast.resize(alloc_len, 0xa5);
+                }
+
+                let keystore2 = get_keystore_service();
+                let sec_level = keystore2.getSecurityLevel(sec_level).unwrap();
+                let alias = test_alias("create_operation_under_memory_pressure");
+                let outcome = classify_sample_sign_op(&sec_level, &alias);
+                std::hint::black_box(&ballast);
+                let _ = delete_app_key(&keystore2, &alias);
+                (allocated, outcome)
+            },
+        )
+    }
+}
+
+/// Create an operation in a child process holding a large memory allocation. Test should either
+/// perform the operation successfully or fail cleanly with an error, and keystore should remain
+/// usable for a subsequent operation created without memory pressure.
+#[test]
+fn keystore2_op_under_memory_pressure_test() {
+    // SAFETY: The test is run in a separate process with no other threads.
+    let (allocated, outcome) =
+        unsafe { create_operation_under_memory_pressure(SecurityLevel::TRUSTED_ENVIRONMENT, 256) };
+    assert_ne!(TestOutcome::InvalidHandle, outcome, "allocated: {}", allocated);
+
+    // SAFETY: The test is run in a separate process with no other threads.
+    let (_, outcome) =
+        unsafe { create_operation_under_memory_pressure(SecurityLevel::TRUSTED_ENVIRONMENT, 0) };
+    assert_eq!(TestOutcome::Ok, outcome);
+}
+
+/// Try to create sign operations with `BLOB` domain key descriptors carrying an empty blob and
+/// garbage blobs. Returns the error each attempt failed with, in that order. Panics if an operation
+/// was created with any of these blobs.
+pub fn assert_null_key_rejected(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+) -> Vec<Error> {
+    let op_params =
+        authorizations::AuthSetBuilder::new().purpose(KeyPurpose::SIGN).digest(Digest::SHA_2_256);
+    [vec![], vec![0xde, 0xad, 0xbe, 0xef], vec![0x5a; 512]]
+        .into_iter()
+        .map(|blob| {
+            let key = key_generations::make_key_descriptor(
+                Domain::BLOB,
+                key_generations::SELINUX_SHELL_NAMESPACE,
+                None,
+                Some(blob),
+            );
+            let result =
+                key_generations::map_ks_error(sec_level.createOperation(&key, &op_params, false));
+            assert!(result.is_err(), "Operation created with bogus key blob {:?}", key.blob);
+            result.unwrap_err()
+        })
+        .collect()
+}
+
+/// Try to create operations using empty and garbage key blobs. Test should fail to create the
+/// operations with `INVALID_KEY_BLOB` error code.
+#[test]
+fn keystore2_op_fails_with_bogus_key_blob() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    for err in assert_null_key_rejected(&sec_level) {
+        assert_eq!(Error::Km(ErrorCode::INVALID_KEY_BLOB), err);
+    }
+}
+
+/// Create a signing operation with an EC key using the given digest and check the operation
+/// parameters returned by the backend. Backends aren't required to return the digest, but if
+/// they do, it has to match the requested one. Returns the echoed digest, if any.
+fn assert_op_params_echo_request(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    digest: Digest,
+) -> Option<Digest> {
+    let keystore2 = get_keystore_service();
+    let alias = test_alias(&format!("assert_op_params_echo_request_{}", digest.0));
+    let key_metadata = key_generations::generate_ec_key(
+        sec_level,
+        Domain::APP,
+        -1,
+        Some(alias.clone()),
+        EcCurve::P_256,
+        digest,
+    )
+    .unwrap();
+
+    let op_response = sec_level
+        .createOperation(
+            &key_metadata.key,
+            &authorizations::AuthSetBuilder::new().purpose(KeyPurpose::SIGN).digest(digest),
+            false,
+        )
+        .unwrap();
+    let echoed_digest = op_response.parameters.as_ref().and_then(get_op_digest);
+    if let Some(op) = op_response.iOperation {
+        abort_operation(&op).unwrap();
+    }
+    delete_app_key(&keystore2, &alias).unwrap();
+
+    if let Some(echoed) = echoed_digest {
+        assert_eq!(digest, echoed, "Operation parameters echo a different digest.");
+    }
+    echoed_digest
+}
+
+/// Create signing operations with each of the SHA-2 digests and check that the operation
+/// parameters do not report a digest other than the requested one.
+#[test]
+fn keystore2_op_params_echo_requested_digest_test() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    for digest in [Digest::SHA_2_224, Digest::SHA_2_256, Digest::SHA_2_384, Digest::SHA_2_512] {
+        assert_op_params_echo_request(&sec_level, digest);
+    }
+}
+
+/// Run the conformance matrix against the TEE backend and print the report. Test should not
+/// report any failing combination; unsupported ones are reported as skipped.
+#[test]
+fn keystore2_conformance_matrix_test() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    let report = run_conformance_matrix(&sec_level);
+    println!("{}", report);
+    assert!(!report.entries.is_empty());
+    assert!(report.failures().is_empty(), "{:?}", report.failures());
+}
+
+/// Finish an operation, then create a new operation with the same key. The finished operation's
+/// handle must keep failing with `INVALID_OPERATION_HANDLE` instead of reaching the new
+/// operation, and the new operation must be unaffected by that attempt. Operation handles are
+/// binder objects, so their identity is compared by binder object. Returns whether the new
+/// operation aliases the finished one.
+fn assert_handle_not_reused_prematurely(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+) -> bool {
+    let alias = test_alias("assert_handle_not_reused_prematurely");
+    let key_metadata = key_generations::generate_ec_p256_signing_key(
+        sec_level,
+        Domain::SELINUX,
+        key_generations::SELINUX_SHELL_NAMESPACE,
+        Some(alias),
+        None,
+    )
+    .unwrap();
+    let op_params =
+        authorizations::AuthSetBuilder::new().purpose(KeyPurpose::SIGN).digest(Digest::SHA_2_256);
+
+    let finished_op =
+        sec_level.createOperation(&key_metadata.key, &op_params, false).unwrap().iOperation;
+    let finished_op = finished_op.expect("Operation should have created successfully.");
+    assert_eq!(Ok(()), key_generations::map_ks_error(perform_sample_sign_operation(&finished_op)));
+
+    let new_op =
+        sec_level.createOperation(&key_metadata.key, &op_params, false).unwrap().iOperation;
+    let new_op = new_op.expect("Operation should have created successfully.");
+    let aliased = finished_op.as_binder() == new_op.as_binder();
+
+    assert_eq!(
+        Err(Error::Km(ErrorCode::INVALID_OPERATION_HANDLE)),
+        key_generations::map_ks_error(finished_op.update(b"my message"))
+    );
+    assert_eq!(Ok(()), key_generations::map_ks_error(perform_sample_sign_operation(&new_op)));
+    aliased
+}
+
+/// Test should verify that a finished operation handle isn't handed out again for a new
+/// operation and keeps rejecting calls after the new operation is created.
+#[test]
+fn keystore2_finished_op_handle_not_reused_test() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    assert!(
+        !assert_handle_not_reused_prematurely(&sec_level),
+        "New operation aliases the finished operation handle."
+    );
+}
+
+/// Percentiles of operation creation latency measured by `measure_op_latency_under_concurrency`.
+#[derive(Debug)]
+pub struct LatencyStats {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    /// Number of operation creations which failed with `BACKEND_BUSY`.
+    pub backend_busy: usize,
+    /// Errors of operation creations which failed with anything other than `BACKEND_BUSY`.
+    pub other_errors: Vec<Error>,
+}
+
+/// Get the given percentile of the sorted latencies.
+fn latency_percentile(sorted_latencies: &[Duration], percentile: usize) -> Duration {
+    sorted_latencies[(sorted_latencies.len() - 1) * percentile / 100]
+}
+
+/// Create `concurrent_ops` operations from as many threads at once, timing each
+/// `createOperation` call. All created operations are aborted once every thread is done.
+/// Returns the p50/p90/p99 latencies along with the number of `BACKEND_BUSY` errors and all other
+/// errors.
+pub fn measure_op_latency_under_concurrency(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    concurrent_ops: usize,
+) -> LatencyStats {
+    assert!(concurrent_ops > 0);
+    let alias = test_alias("measure_op_latency_under_concurrency");
+    let key_metadata = key_generations::generate_ec_p256_signing_key(
+        sec_level,
+        Domain::SELINUX,
+        key_generations::SELINUX_SHELL_NAMESPACE,
+        Some(alias),
+        None,
+    )
+    .unwrap();
+
+    let handles: Vec<_> = (0..concurrent_ops)
+        .map(|_| {
+            let sec_level = sec_level.clone();
+            let key = key_metadata.key.clone();
+            thread::spawn(move || {
+                let start = Instant::now();
+                let result = key_generations::map_ks_error(
+                    sec_level.createOperation(
+                        &key,
+                        &authorizations::AuthSetBuilder::new()
+                            .purpose(KeyPurpose::SIGN)
+                            .digest(Digest::SHA_2_256),
+                        false,
+                    ),
+                );
+                (start.elapsed(), result)
+            })
+        })
+        .collect();
+    let results: Vec<_> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+
+    let mut latencies: Vec<Duration> = results.iter().map(|(latency, _)| *latency).collect();
+    latencies.sort();
+    let backend_busy = results
+        .iter()
+        .filter(|(_, result)| matches!(result, Err(Error::Rc(ResponseCode::BACKEND_BUSY))))
+        .count();
+    let mut other_errors = Vec::new();
+    for (_, result) in results {
+        match result {
+            Ok(CreateOperationResponse { iOperation: Some(op), .. }) => {
+                // Operations may have been pruned by the ones created after them.
+                let _ = abort_operation(&op);
+            }
+            Ok(_) | Err(Error::Rc(ResponseCode::BACKEND_BUSY)) => {}
+            Err(e) => other_errors.push(e),
+        }
+    }
+
+    LatencyStats {
+        p50: latency_percentile(&latencies, 50),
+        p90: latency_percentile(&latencies, 90),
+        p99: latency_percentile(&latencies, 99),
+        backend_busy,
+        other_errors,
+    }
+}
+
+/// Create operations concurrently from multiple threads and report the latency percentiles of
+/// operation creation. Test should create every operation either successfully or fail with
+/// `BACKEND_BUSY`, and at least one of them must succeed.
+#[test]
+fn keystore2_op_creation_latency_under_concurrency_test() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    let concurrent_ops = 16;
+    let stats = measure_op_latency_under_concurrency(&sec_level, concurrent_ops);
+    println!(
+        "createOperation latency: p50 {:?}, p90 {:?}, p99 {:?}, BACKEND_BUSY {}",
+        stats.p50, stats.p90, stats.p99, stats.backend_busy
+    );
+    assert!(stats.other_errors.is_empty(), "createOperation failed: {:?}", stats.other_errors);
+    assert!(
+        stats.backend_busy < concurrent_ops,
+        "All {} operations failed with BACKEND_BUSY.",
+        concurrent_ops
+    );
+}
+
+/// Create `n` operations with the same key, abort the middle one and then complete the remaining
+/// operations from one thread each. The aborted operation must no longer be usable while all
+/// other operations must complete successfully. Returns the index and error of every other
+/// operation that failed.
+fn assert_abort_isolation(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    n: usize,
+) -> Vec<(usize, Error)> {
+    assert!(n >= 3);
+    let alias = test_alias("assert_abort_isolation");
+    let key_metadata = key_generations::generate_ec_p256_signing_key(
+        sec_level,
+        Domain::SELINUX,
+        key_generations::SELINUX_SHELL_NAMESPACE,
+        Some(alias),
+        None,
+    )
+    .unwrap();
+    let op_params =
+        authorizations::AuthSetBuilder::new().purpose(KeyPurpose::SIGN).digest(Digest::SHA_2_256);
+
+    let mut ops: Vec<_> = (0..n)
+        .map(|_| {
+            sec_level
+                .createOperation(&key_metadata.key, &op_params, false)
+                .unwrap()
+                .iOperation
+                .expect("Operation should have created successfully.")
+        })
+        .collect();
+
+    let aborted_op = ops.remove(n / 2);
+    assert_eq!(Ok(()), key_generations::map_ks_error(abort_operation(&aborted_op)));
+
+    let handles: Vec<_> = ops
+        .into_iter()
+        .enumerate()
+        .map(|(i, op)| {
+            let index = if i < n / 2 { i } else { i + 1 };
+            thread::spawn(move || {
+                (index, key_generations::map_ks_error(perform_sample_sign_operation(&op)))
+            })
+        })
+        .collect();
+    let failures = handles
+        .into_iter()
+        .filter_map(|handle| match handle.join().unwrap() {
+            (_, Ok(())) => None,
+            (index, Err(e)) => Some((index, e)),
+        })
+        .collect();
+
+    assert_eq!(
+        Err(Error::Km(ErrorCode::INVALID_OPERATION_HANDLE)),
+        key_generations::map_ks_error(aborted_op.update(b"my message"))
+    );
+    failures
+}
+
+/// Create `n` operations with the same key from the calling uid, one thread each, and sign a
+/// distinct message in every operation. Each signature is then verified against the message of
+/// its own operation, which catches inputs or results leaking between operations of the same
+/// owner. Returns the index of every operation that failed, along with its error, or `None` if
+/// the operation completed but its signature does not match its own message.
+fn assert_same_uid_concurrent_ops(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    n: usize,
+) -> Vec<(usize, Option<Error>)> {
+    let alias = test_alias("assert_same_uid_concurrent_ops");
+    let key_metadata = key_generations::generate_ec_p256_signing_key(
+        sec_level,
+        Domain::SELINUX,
+        key_generations::SELINUX_SHELL_NAMESPACE,
+        Some(alias),
+        None,
+    )
+    .unwrap();
+
+    let handles: Vec<_> = (0..n)
+        .map(|i| {
+            let sec_level = sec_level.clone();
+            let key = key_metadata.key.clone();
+            thread::spawn(move || {
+                let message = format!("message from operation {i}").into_bytes();
+                let result = key_generations::map_ks_error(
+                    sec_level.createOperation(
+                        &key,
+                        &authorizations::AuthSetBuilder::new()
+                            .purpose(KeyPurpose::SIGN)
+                            .digest(Digest::SHA_2_256),
+                        false,
+                    ),
+                )
+                .and_then(|op_response| {
+                    let op = op_response
+                        .iOperation
+                        .expect("Operation should have created successfully.");
+                    key_generations::map_ks_error(op.update(&message))?;
+                    key_generations::map_ks_error(op.finish(None, None))
+                });
+                (message, result)
+            })
+        })
+        .collect();
+
+    let cert = X509::from_der(key_metadata.certificate.as_ref().unwrap()).unwrap();
+    let pub_key = cert.public_key().unwrap();
+    handles
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, handle)| match handle.join().unwrap() {
+            (message, Ok(Some(sig))) => {
+                let mut verifier = Verifier::new(MessageDigest::sha256(), &pub_key).unwrap();
+                verifier.update(&message).unwrap();
+                if verifier.verify(&sig).unwrap() {
+                    None
+                } else {
+                    Some((i, None))
+                }
+            }
+            (_, Ok(None)) => Some((i, None)),
+            (_, Err(e)) => Some((i, Some(e))),
+        })
+        .collect()
+}
+
+/// Create several operations from the same uid concurrently and sign a different message in each
+/// of them. Test should produce a valid signature of its own message in every operation.
+#[test]
+fn keystore2_same_uid_concurrent_ops_test() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    let failures = assert_same_uid_concurrent_ops(&sec_level, 4);
+    assert!(failures.is_empty(), "Operations interfered with each other: {:?}", failures);
+}
+
+/// Create several operations, abort one of them and complete the others concurrently. Test
+/// should complete all operations except the aborted one successfully.
+#[test]
+fn keystore2_abort_op_isolation_test() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    let failures = assert_abort_isolation(&sec_level, 8);
+    assert!(failures.is_empty(), "Operations failed after aborting a sibling: {:?}", failures);
+}
+
+/// Record an HMAC signing session in an `OperationTranscript` and replay it against a fresh
+/// operation on the same key. Since HMAC is deterministic, test should produce an identical
+/// transcript on replay.
+#[test]
+fn keystore2_op_transcript_replay_test() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+    let alias = test_alias("keystore2_op_transcript_replay_test");
+    let key_metadata =
+        key_generations::generate_hmac_key(&sec_level, &alias, 128, 128, Digest::SHA_2_256)
+            .unwrap();
+    let op_params = authorizations::AuthSetBuilder::new()
+        .purpose(KeyPurpose::SIGN)
+        .digest(Digest::SHA_2_256)
+        .mac_length(128);
+
+    let op = sec_level.createOperation(&key_metadata.key, &op_params, false).unwrap().iOperation;
+    let op = op.expect("Operation should have created successfully.");
+    let mut transcript = OperationTranscript::new();
+    transcript.call(&op, OperationCall::Update(b"my message".to_vec())).unwrap();
+    transcript.call(&op, OperationCall::Update(b" in two parts".to_vec())).unwrap();
+    let mac = transcript.call(&op, OperationCall::Finish { input: None, signature: None });
+    assert!(mac.unwrap().is_some());
+
+    let op = sec_level.createOperation(&key_metadata.key, &op_params, false).unwrap().iOperation;
+    let op = op.expect("Operation should have created successfully.");
+    assert_eq!(transcript, transcript.replay(&op));
+    delete_app_key(&keystore2, &alias).unwrap();
+}
+
+/// Create forced operations until the backend fails with `BACKEND_BUSY`, since forced operations
+/// can't prune other forced operations. All created operations are aborted afterwards. Returns
+/// the number of forced operations created before `BACKEND_BUSY`; this is the number of slots
+/// which are free at the time of the call.
+pub fn measure_max_forced_ops(sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>) -> i32 {
+    let alias = test_alias("measure_max_forced_ops");
+    let key_metadata = key_generations::generate_ec_p256_signing_key(
+        sec_level,
+        Domain::SELINUX,
+        key_generations::SELINUX_SHELL_NAMESPACE,
+        Some(alias),
+        None,
+    )
+    .unwrap();
+
+    let ops = fill_op_slots_with_forced_ops(sec_level, &key_metadata.key);
+    for op in &ops {
+        assert_eq!(Ok(()), key_generations::map_ks_error(abort_operation(op)));
+    }
+    ops.len() as i32
+}
+
+/// Create forced operations until the operation slots are exhausted and report the number of
+/// forced operations the backend allows.
+#[test]
+fn keystore2_measure_max_forced_ops_test() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    let max_forced_ops = measure_max_forced_ops(&sec_level);
+    println!("Backend allows {} concurrent forced operations.", max_forced_ops);
+    assert!(max_forced_ops > 0);
+}
+
+/// Open `n` regular operations with the same key and use them in a staggered order, so that the
+/// operation at index 1 is the least recently used one rather than the first one created. All of
+/// them have the same owner and age, which gives them equal pruning priority. Then forced
+/// operations are created until exactly one more slot than was free is needed. Returns the index
+/// of the least recently used operation along with the indices of the operations which were
+/// actually pruned, or `None` if the backend has fewer than `n` free slots.
+pub fn assert_lru_pruning(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    n: usize,
+) -> Option<(usize, Vec<usize>)> {
+    assert!(n >= 2);
+    let free_slots = measure_max_forced_ops(sec_level) as usize;
+    if free_slots < n {
+        return None;
+    }
+
+    let alias = test_alias("assert_lru_pruning");
+    let key_metadata = key_generations::generate_ec_p256_signing_key(
+        sec_level,
+        Domain::SELINUX,
+        key_generations::SELINUX_SHELL_NAMESPACE,
+        Some(alias),
+        None,
+    )
+    .unwrap();
+    let op_params =
+        authorizations::AuthSetBuilder::new().purpose(KeyPurpose::SIGN).digest(Digest::SHA_2_256);
+
+    let ops: Vec<_> = (0..n)
+        .map(|_| {
+            sec_level
+                .createOperation(&key_metadata.key, &op_params, false)
+                .unwrap()
+                .iOperation
+                .expect("Operation should have created successfully.")
+        })
+        .collect();
+    // Use the operations starting at index 1, so that it is the least recently used one.
+    let use_order: Vec<usize> = (1..n).chain(0..1).collect();
+    for &i in &use_order {
+        ops[i].update(b"my message").unwrap();
+        thread::sleep(Duration::from_millis(20));
+    }
+    let lru = use_order[0];
+
+    // The forced operations fill the remaining slots and then need to prune one operation.
+    let forced_ops: Vec<_> = (0..=free_slots - n)
+        .filter_map(|_| {
+            match key_generations::map_ks_error(sec_level.createOperation(
+                &key_metadata.key,
+                &op_params,
+                true,
+            )) {
+                Ok(CreateOperationResponse { iOperation: Some(op), .. }) => Some(op),
+                // Another client may have taken a slot in the meantime.
+                Err(Error::Rc(ResponseCode::BACKEND_BUSY)) => None,
+                result => panic!("Unexpected result of forced operation creation: {:?}", result),
+            }
+        })
+        .collect();
+
+    let pruned = ops
+        .iter()
+        .enumerate()
+        .filter_map(|(i, op)| match key_generations::map_ks_error(op.update(b"my message")) {
+            Err(Error::Km(ErrorCode::INVALID_OPERATION_HANDLE)) => Some(i),
+            _ => {
+                assert_eq!(Ok(()), key_generations::map_ks_error(abort_operation(op)));
+                None
+            }
+        })
+        .collect();
+    for op in &forced_ops {
+        assert_eq!(Ok(()), key_generations::map_ks_error(abort_operation(op)));
+    }
+    Some((lru, pruned))
+}
+
+/// Create several equal priority operations with staggered last use times and push them out of
+/// their slots with forced operations. Test should see the least recently used operation pruned.
+#[test]
+fn keystore2_lru_op_pruned_test() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    match assert_lru_pruning(&sec_level, 4) {
+        Some((lru, pruned)) => assert_eq!(vec![lru], pruned),
+        None => log_test_skip("the backend has fewer than 4 free operation slots"),
+    }
+}
+
+/// Generate a signing key, create an operation with it and complete a sample signing operation.
+/// Returns the finished operation handle.
+fn create_finished_sign_op(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    alias: String,
+) -> binder::Strong<dyn IKeystoreOperation> {
+    let key_metadata = key_generations::generate_ec_p256_signing_key(
+        sec_level,
+        Domain::SELINUX,
+        key_generations::SELINUX_SHELL_NAMESPACE,
+        Some(alias),
+        None,
+    )
+    .unwrap();
+
+    let op = sec_level
+        .createOperation(
+            &key_metadata.key,
+            &authorizations::AuthSetBuilder::new()
+                .purpose(KeyPurpose::SIGN)
+                .digest(Digest::SHA_2_256),
+            false,
+        )
+        .unwrap()
+        .iOperation
+        .expect("Operation should have created successfully.");
+    assert_eq!(Ok(()), key_generations::map_ks_error(perform_sample_sign_operation(&op)));
+    op
+}
+
+/// Finish an operation and then try to abort it. Aborting must fail. Returns the error of the
+/// abort call.
+fn assert_abort_after_finish_fails(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+) -> Error {
+    let op = create_finished_sign_op(sec_level, test_alias("assert_abort_after_finish_fails"));
+
+    key_generations::map_ks_error(abort_operation(&op))
+        .expect_err("Finished operation was aborted successfully.")
+}
+
+/// Test should fail to abort an operation which has already been finished.
+#[test]
+fn keystore2_abort_after_finish_fails_test() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    assert_eq!(
+        Error::Km(ErrorCode::INVALID_OPERATION_HANDLE),
+        assert_abort_after_finish_fails(&sec_level)
+    );
+}
+
+/// Finish an operation and then try to finish it again. The second finish must fail. Returns the
+/// error of the second finish call.
+fn assert_double_finish_fails(sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>) -> Error {
+    let op = create_finished_sign_op(sec_level, test_alias("assert_double_finish_fails"));
+
+    key_generations::map_ks_error(op.finish(None, None))
+        .expect_err("Finished operation was finished again successfully.")
+}
+
+/// Test should fail to finish an operation which has already been finished.
+#[test]
+fn keystore2_double_finish_fails_test() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    assert_eq!(
+        Error::Km(ErrorCode::INVALID_OPERATION_HANDLE),
+        assert_double_finish_fails(&sec_level)
+    );
+}
+
+/// Finish an operation and then try to pass more input to it. The update must fail. Returns the
+/// error of the update call.
+fn assert_update_after_finish_fails(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+) -> Error {
+    let op = create_finished_sign_op(sec_level, test_alias("assert_update_after_finish_fails"));
+
+    key_generations::map_ks_error(op.update(b"my message"))
+        .expect_err("Finished operation accepted more input.")
+}
+
+/// Test should fail to update an operation which has already been finished.
+#[test]
+fn keystore2_update_after_finish_fails_test() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    assert_eq!(
+        Error::Km(ErrorCode::INVALID_OPERATION_HANDLE),
+        assert_update_after_finish_fails(&sec_level)
+    );
+}
+
+/// Create an operation and abort it. Test should see the aborted operation counted in the
+/// keystore metrics. Keystore records the outcome once it drops the operation, so the metrics are
+/// polled for a short while.
+#[test]
+fn keystore2_op_abort_counted_in_metrics_test() {
+    const MAX_POLLS: u32 = 10;
+    let before = get_keystore_metrics().unwrap();
+
+    let alias = test_alias("keystore2_op_abort_counted_in_metrics_test");
+    let op_response = create_signing_operation(
+        ForcedOp(false),
+        KeyPurpose::SIGN,
+        Digest::SHA_2_256,
+        Domain::SELINUX,
+        key_generations::SELINUX_SHELL_NAMESPACE,
+        Some(alias),
+    )
+    .unwrap();
+    let op = op_response.iOperation.expect("Operation should have created successfully.");
+    assert_eq!(Ok(()), key_generations::map_ks_error(abort_operation(&op)));
+    drop(op);
+
+    for _ in 0..MAX_POLLS {
+        let after = get_keystore_metrics().unwrap();
+        if after.operations_aborted > before.operations_aborted {
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("Aborted operation not counted in keystore metrics: {:?}", get_keystore_metrics());
+}
+
+/// Pull the keystore metrics from a child process, so that the calling process can still fork
+/// children afterwards without having used binder itself.
+///
+/// # Safety
+///
+/// Must only be called from a single-threaded process.
+unsafe fn get_keystore_metrics_in_child() -> KeystoreMetrics {
+    // SAFETY: The caller guarantees that there are no other threads.
+    unsafe {
+        run_as::run_as(key_generations::TARGET_SU_CTX, Uid::from_raw(0), Gid::from_raw(0), || {
+            get_keystore_metrics().unwrap()
+        })
+    }
+}
+
+/// Let forced operations prune regular operations using
+/// `assert_forced_prunes_regular_before_forced`. Test should see the pruned operations counted in
+/// the keystore metrics. Keystore records the outcome once the pruned operations are dropped
+/// along with the child processes owning them, so the metrics are polled for a short while.
+#[test]
+fn keystore2_forced_op_pruning_counted_in_metrics_test() {
+    const MAX_POLLS: u32 = 10;
+    // SAFETY: The test is run in a separate process with no other threads.
+    let before = unsafe { get_keystore_metrics_in_child() };
+
+    // SAFETY: The test is run in a separate process with no other threads.
+    let summary = unsafe { assert_forced_prunes_regular_before_forced() };
+
+    for _ in 0..MAX_POLLS {
+        let after = get_keystore_metrics().unwrap();
+        if after.operations_pruned > before.operations_pruned {
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!(
+        "Pruned operations {:?} not counted in keystore metrics: {:?}",
+        summary,
+        get_keystore_metrics()
+    );
+}
+
+/// Result of `assert_handle_exhaustion_distinct_from_busy`.
+#[derive(Debug, Default)]
+pub struct ExhaustionReport {
+    /// Number of operations successfully created and aborted back to back.
+    pub churned_ops: usize,
+    /// Error which stopped the churn phase early, if any.
+    pub churn_error: Option<Error>,
+    /// Number of operations created while keeping all previous operations open.
+    pub held_ops: usize,
+    /// Number of held operations which were pruned to make room for later ones.
+    pub pruned_ops: usize,
+    /// Error which stopped the hold phase early, if any.
+    pub hold_error: Option<Error>,
+}
+
+/// Probe the two ways operation creation can run out of resources with the same key. First,
+/// `churn_ops` operations are created and immediately aborted, so that operation handles are
+/// consumed quickly while at most one KeyMint operation slot is in use. Then up to `held_ops`
+/// operations are created without releasing any of them, which exhausts the operation slots.
+/// Since all operations have the same owner, keystore is expected to prune the caller's own
+/// operations rather than fail with `BACKEND_BUSY` in the second phase.
+pub fn assert_handle_exhaustion_distinct_from_busy(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    churn_ops: usize,
+    held_ops: usize,
+) -> ExhaustionReport {
+    let alias = test_alias("assert_handle_exhaustion_distinct_from_busy");
+    let key_metadata = key_generations::generate_ec_p256_signing_key(
+        sec_level,
+        Domain::SELINUX,
+        key_generations::SELINUX_SHELL_NAMESPACE,
+        Some(alias),
+        None,
+    )
+    .unwrap();
+    let op_params =
+        authorizations::AuthSetBuilder::new().purpose(KeyPurpose::SIGN).digest(Digest::SHA_2_256);
+    let create_op = || {
+        key_generations::map_ks_error(sec_level.createOperation(
+            &key_metadata.key,
+            &op_params,
+            false,
+        ))
+        .map(|op_response| {
+            op_response.iOperation.expect("Operation should have created successfully.")
+        })
+    };
+
+    let mut report = ExhaustionReport::default();
+
+    for _ in 0..churn_ops {
+        match create_op() {
+            Ok(op) => {
+                // The operation may already have been pruned by a concurrent client.
+                let _ = abort_operation(&op);
+                report.churned_ops += 1;
+            }
+            Err(e) => {
+                report.churn_error = Some(e);
+                break;
+            }
+        }
+    }
+
+    let mut ops = Vec::new();
+    for _ in 0..held_ops {
+        match create_op() {
+            Ok(op) => ops.push(op),
+            Err(e) => {
+                report.hold_error = Some(e);
+                break;
+            }
+        }
+    }
+    report.held_ops = ops.len();
+    for op in ops {
+        match key_generations::map_ks_error(op.update(b"my message")) {
+            Err(Error::Km(ErrorCode::INVALID_OPERATION_HANDLE)) => report.pruned_ops += 1,
+            _ => {
+                let _ = abort_operation(&op);
+            }
+        }
+    }
+    report
+}
+
+/// Create and abort many operations back to back, then keep creating operations without
+/// releasing them. Test should never run out of operation handles, and exhausting the operation
+/// slots should only prune the caller's own operations or fail with `BACKEND_BUSY`.
+#[test]
+fn keystore2_op_handle_exhaustion_distinct_from_busy_test() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    let report = assert_handle_exhaustion_distinct_from_busy(&sec_level, 500, 64);
+    println!("Operation exhaustion report: {:?}", report);
+    assert_eq!(None, report.churn_error);
+    assert!(matches!(report.hold_error, None | Some(Error::Rc(ResponseCode::BACKEND_BUSY))));
+}
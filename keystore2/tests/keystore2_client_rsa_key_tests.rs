@@ -25,7 +25,9 @@ use keystore2_test_utils::{
     authorizations, get_keystore_service, key_generations, key_generations::Error,
 };
 
-use crate::keystore2_client_test_utils::{delete_app_key, perform_sample_sign_operation, ForcedOp};
+use crate::keystore2_client_test_utils::{
+    delete_app_key, perform_sample_sign_operation, test_alias, ForcedOp,
+};
 
 /// This macro is used for creating signing key operation tests using digests and paddings
 /// for various key sizes.
@@ -1893,3 +1895,58 @@ fn keystore2_rsa_gen_keys_unsupported_size() {
     assert!(result.is_err());
     assert_eq!(Error::Km(ErrorCode::UNSUPPORTED_KEY_SIZE), result.unwrap_err());
 }
+
+/// Generate an RSA signing key authorized for `RSA_PKCS1_1_5_SIGN` padding only and try to create
+/// a signing operation with it requesting `RSA_PSS` padding. Creating the operation must fail.
+/// Returns the error reported for it.
+fn assert_op_padding_must_match_key(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+) -> Error {
+    let keystore2 = get_keystore_service();
+    let alias = test_alias("assert_op_padding_must_match_key");
+    let key_metadata = key_generations::generate_rsa_key(
+        sec_level,
+        Domain::APP,
+        -1,
+        Some(alias.clone()),
+        &key_generations::KeyParams {
+            key_size: 2048,
+            purpose: vec![KeyPurpose::SIGN, KeyPurpose::VERIFY],
+            padding: Some(PaddingMode::RSA_PKCS1_1_5_SIGN),
+            digest: Some(Digest::SHA_2_256),
+            mgf_digest: None,
+            block_mode: None,
+            att_challenge: None,
+        },
+        None,
+    )
+    .unwrap();
+
+    let result = key_generations::map_ks_error(
+        sec_level.createOperation(
+            &key_metadata.key,
+            &authorizations::AuthSetBuilder::new()
+                .purpose(KeyPurpose::SIGN)
+                .digest(Digest::SHA_2_256)
+                .padding_mode(PaddingMode::RSA_PSS),
+            false,
+        ),
+    );
+    delete_app_key(&keystore2, &alias).unwrap();
+
+    result.expect_err("Operation with PSS padding was created using a PKCS1 key.")
+}
+
+/// Generate an RSA key with `RSA_PKCS1_1_5_SIGN` padding and try to create a signing operation
+/// with `RSA_PSS` padding. Test should fail to create an operation with an error code
+/// `INCOMPATIBLE_PADDING_MODE`.
+#[test]
+fn keystore2_rsa_pkcs1_key_op_fails_pss_padding() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    assert_eq!(
+        Error::Km(ErrorCode::INCOMPATIBLE_PADDING_MODE),
+        assert_op_padding_must_match_key(&sec_level)
+    );
+}
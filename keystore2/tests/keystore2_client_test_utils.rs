@@ -12,12 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use nix::unistd::{Gid, Uid};
+use nix::unistd::{getuid, Gid, Uid};
 use serde::{Deserialize, Serialize};
 
+use std::collections::HashMap;
+use std::fmt;
 use std::path::PathBuf;
 use std::process::{Command, Output};
+use std::sync::{Mutex, OnceLock};
 
+use openssl::asn1::Asn1Time;
 use openssl::bn::BigNum;
 use openssl::encrypt::Encrypter;
 use openssl::error::ErrorStack;
@@ -25,16 +29,18 @@ use openssl::hash::MessageDigest;
 use openssl::nid::Nid;
 use openssl::pkey::PKey;
 use openssl::pkey::Public;
+use openssl::rand::rand_bytes;
 use openssl::rsa::Padding;
 use openssl::sign::Verifier;
-use openssl::x509::X509;
+use openssl::symm::{encrypt_aead, Cipher};
+use openssl::x509::{X509Name, X509};
 
 use binder::wait_for_interface;
 
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
-    BlockMode::BlockMode, Digest::Digest, ErrorCode::ErrorCode,
-    KeyParameterValue::KeyParameterValue, KeyPurpose::KeyPurpose, PaddingMode::PaddingMode,
-    SecurityLevel::SecurityLevel, Tag::Tag,
+    Algorithm::Algorithm, BlockMode::BlockMode, Digest::Digest, EcCurve::EcCurve,
+    ErrorCode::ErrorCode, IKeyMintDevice::IKeyMintDevice, KeyParameterValue::KeyParameterValue,
+    KeyPurpose::KeyPurpose, PaddingMode::PaddingMode, SecurityLevel::SecurityLevel, Tag::Tag,
 };
 use android_system_keystore2::aidl::android::system::keystore2::{
     CreateOperationResponse::CreateOperationResponse, Domain::Domain,
@@ -43,12 +49,21 @@ use android_system_keystore2::aidl::android::system::keystore2::{
     KeyParameters::KeyParameters, ResponseCode::ResponseCode,
 };
 
+use android_hardware_security_secureclock::aidl::android::hardware::security::secureclock::{
+    ISecureClock::BpSecureClock, ISecureClock::ISecureClock, TimeStampToken::TimeStampToken,
+};
+use android_security_metrics::aidl::android::security::metrics::{
+    AtomID::AtomID, KeystoreAtomPayload::KeystoreAtomPayload, Outcome::Outcome,
+};
 use packagemanager_aidl::aidl::android::content::pm::IPackageManagerNative::IPackageManagerNative;
 
 use keystore2_test_utils::{
-    authorizations, get_keystore_service, key_generations, key_generations::Error, run_as,
+    authorizations, get_keystore_maintenance_service, get_keystore_metrics_service,
+    get_keystore_service, key_generations, key_generations::Error, run_as,
 };
 
+use keystore2_test_utils::ffi_test_utils::create_wrapped_key;
+
 /// This enum is used to communicate between parent and child processes.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum TestOutcome {
@@ -62,6 +77,127 @@ pub enum TestOutcome {
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct BarrierReached;
 
+/// A call made on an operation, as recorded by `OperationTranscript`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum OperationCall {
+    Update(Vec<u8>),
+    UpdateAad(Vec<u8>),
+    Finish { input: Option<Vec<u8>>, signature: Option<Vec<u8>> },
+}
+
+/// Records the calls made on an operation along with their outputs, so that the same sequence of
+/// calls can be saved and replayed against a fresh operation. Errors are recorded as their
+/// `Debug` representation.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct OperationTranscript {
+    pub entries: Vec<(OperationCall, Result<Option<Vec<u8>>, String>)>,
+}
+
+impl OperationTranscript {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Execute the given call on the operation and record it along with its output.
+    pub fn call(
+        &mut self,
+        op: &binder::Strong<dyn IKeystoreOperation>,
+        call: OperationCall,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let result = key_generations::map_ks_error(match &call {
+            OperationCall::Update(input) => op.update(input),
+            OperationCall::UpdateAad(aad) => op.updateAad(aad).map(|_| None),
+            OperationCall::Finish { input, signature } => {
+                op.finish(input.as_deref(), signature.as_deref())
+            }
+        });
+        let recorded = match &result {
+            Ok(output) => Ok(output.clone()),
+            Err(e) => Err(format!("{:?}", e)),
+        };
+        self.entries.push((call, recorded));
+        result
+    }
+
+    /// Replay the recorded calls against the given operation, returning the new transcript so
+    /// that it can be compared with this one.
+    pub fn replay(&self, op: &binder::Strong<dyn IKeystoreOperation>) -> OperationTranscript {
+        let mut transcript = OperationTranscript::new();
+        for (call, _) in &self.entries {
+            let _ = transcript.call(op, call.clone());
+        }
+        transcript
+    }
+}
+
+/// Operation counters aggregated over all security levels from the keystore metrics atoms.
+/// The counters are cumulative, so tests compare snapshots taken before and after an action.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KeystoreMetrics {
+    /// Number of operations with any outcome.
+    pub operations_total: i32,
+    pub operations_succeeded: i32,
+    pub operations_aborted: i32,
+    pub operations_pruned: i32,
+    pub operations_failed: i32,
+}
+
+/// Outcome of a single algorithm/parameter combination of the conformance matrix.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ConformanceResult {
+    Pass,
+    /// The backend reported the combination as unsupported.
+    Skip(String),
+    Fail(String),
+}
+
+/// A named combination of the conformance matrix along with its outcome.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ConformanceEntry {
+    pub combination: String,
+    pub result: ConformanceResult,
+}
+
+/// Report produced by `run_conformance_matrix`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ConformanceReport {
+    pub entries: Vec<ConformanceEntry>,
+}
+
+impl ConformanceReport {
+    /// Entries whose combination failed.
+    pub fn failures(&self) -> Vec<&ConformanceEntry> {
+        self.entries.iter().filter(|e| matches!(e.result, ConformanceResult::Fail(_))).collect()
+    }
+
+    fn count(&self, f: impl Fn(&ConformanceResult) -> bool) -> usize {
+        self.entries.iter().filter(|e| f(&e.result)).count()
+    }
+}
+
+impl fmt::Display for ConformanceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in &self.entries {
+            match &entry.result {
+                ConformanceResult::Pass => writeln!(f, "PASS {}", entry.combination)?,
+                ConformanceResult::Skip(reason) => {
+                    writeln!(f, "SKIP {} ({})", entry.combination, reason)?
+                }
+                ConformanceResult::Fail(reason) => {
+                    writeln!(f, "FAIL {} ({})", entry.combination, reason)?
+                }
+            }
+        }
+        write!(
+            f,
+            "{} passed, {} skipped, {} failed",
+            self.count(|r| *r == ConformanceResult::Pass),
+            self.count(|r| matches!(r, ConformanceResult::Skip(_))),
+            self.count(|r| matches!(r, ConformanceResult::Fail(_))),
+        )
+    }
+}
+
 /// Forced operation.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ForcedOp(pub bool);
@@ -73,6 +209,10 @@ pub const PACKAGE_MANAGER_NATIVE_SERVICE: &str = "package_native";
 pub const APP_ATTEST_KEY_FEATURE: &str = "android.hardware.keystore.app_attest_key";
 pub const DEVICE_ID_ATTESTATION_FEATURE: &str = "android.software.device_id_attestation";
 
+/// Setting this environment variable enables logging of the operation lifecycle in the
+/// operation helpers below.
+pub const OP_TRACE_ENV_VAR: &str = "KS2_TEST_OP_TRACE";
+
 /// Determines whether app_attest_key_feature is supported or not.
 pub fn app_attest_key_feature_exists() -> bool {
     let pm = wait_for_interface::<dyn IPackageManagerNative>(PACKAGE_MANAGER_NATIVE_SERVICE)
@@ -102,6 +242,135 @@ pub fn skip_device_id_attest_tests() -> bool {
     get_vsr_api_level() < 34 && gsi_marker.as_path().is_file()
 }
 
+/// Optional features supported by a KeyMint backend.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyMintFeatures {
+    /// Keys with purpose `ATTEST_KEY` can be used to sign attestations of other keys.
+    pub attest_key: bool,
+    /// EC keys with curve `CURVE_25519` can be generated.
+    pub curve_25519: bool,
+    /// Device-unique attestation can be requested.
+    pub device_unique_attestation: bool,
+}
+
+/// Query the KeyMint backend of the given security level with `getHardwareInfo` and return its
+/// version number along with the optional features it supports. Returns version `0` with no
+/// features if no KeyMint instance is declared for the security level, e.g. the device is backed
+/// by Keymaster or has no StrongBox.
+pub fn get_keymint_version(sec_level: SecurityLevel) -> (u32, KeyMintFeatures) {
+    let instance = match sec_level {
+        SecurityLevel::TRUSTED_ENVIRONMENT => "default",
+        SecurityLevel::STRONGBOX => "strongbox",
+        _ => return (0, KeyMintFeatures::default()),
+    };
+    let service_name = format!("android.hardware.security.keymint.IKeyMintDevice/{}", instance);
+    if !binder::is_declared(&service_name).unwrap_or(false) {
+        return (0, KeyMintFeatures::default());
+    }
+
+    let keymint = wait_for_interface::<dyn IKeyMintDevice>(&service_name)
+        .expect("Failed to get KeyMint service.");
+    let hw_info = keymint.getHardwareInfo().expect("getHardwareInfo failed.");
+    let version: u32 = hw_info.versionNumber.try_into().unwrap_or(0);
+
+    let features = KeyMintFeatures {
+        attest_key: app_attest_key_feature_exists(),
+        // `CURVE_25519` support is mandatory from KeyMint v2 onwards.
+        curve_25519: version >= 200,
+        // Device-unique attestation is only specified for StrongBox implementations.
+        device_unique_attestation: hw_info.securityLevel == SecurityLevel::STRONGBOX,
+    };
+    (version, features)
+}
+
+/// Digests defined by KeyMint, probed by `get_supported_digests`.
+const ALL_DIGESTS: &[Digest] = &[
+    Digest::NONE,
+    Digest::MD5,
+    Digest::SHA1,
+    Digest::SHA_2_224,
+    Digest::SHA_2_256,
+    Digest::SHA_2_384,
+    Digest::SHA_2_512,
+];
+
+/// Probing results per security level.
+type SupportCache<T> = Mutex<HashMap<SecurityLevel, Vec<T>>>;
+
+/// Get the candidates for which `probe` succeeds with the given security level. The results are
+/// cached per security level, so that each candidate is probed only once.
+fn get_supported<T: Copy>(
+    cache: &'static OnceLock<SupportCache<T>>,
+    sec_level: SecurityLevel,
+    candidates: &[T],
+    probe: impl Fn(&binder::Strong<dyn IKeystoreSecurityLevel>, T) -> bool,
+) -> Vec<T> {
+    let mut cache = cache.get_or_init(Default::default).lock().unwrap();
+    cache
+        .entry(sec_level)
+        .or_insert_with(|| {
+            let keystore2 = get_keystore_service();
+            let sec_level = keystore2.getSecurityLevel(sec_level).unwrap();
+            candidates.iter().copied().filter(|candidate| probe(&sec_level, *candidate)).collect()
+        })
+        .clone()
+}
+
+/// Try to generate an EC signing key with the given curve and digest and delete it again.
+/// Returns whether the key was generated.
+fn probe_ec_key_generation(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    ec_curve: EcCurve,
+    digest: Digest,
+) -> bool {
+    let alias = test_alias(&format!("probe_ec_key_generation_{}_{}", ec_curve.0, digest.0));
+    let result = key_generations::generate_ec_key(
+        sec_level,
+        Domain::APP,
+        -1,
+        Some(alias.clone()),
+        ec_curve,
+        digest,
+    );
+    if result.is_ok() {
+        delete_app_key(&get_keystore_service(), &alias).unwrap();
+    }
+    result.is_ok()
+}
+
+/// Get the digests the given security level supports for EC P-256 signing keys, by probing key
+/// generation with every digest.
+pub fn get_supported_digests(sec_level: SecurityLevel) -> Vec<Digest> {
+    static SUPPORTED_DIGESTS: OnceLock<SupportCache<Digest>> = OnceLock::new();
+    get_supported(&SUPPORTED_DIGESTS, sec_level, ALL_DIGESTS, |sec_level, digest| {
+        probe_ec_key_generation(sec_level, EcCurve::P_256, digest)
+    })
+}
+
+/// Get the EC curves the given security level supports, by probing key generation with every
+/// curve.
+pub fn get_supported_curves(sec_level: SecurityLevel) -> Vec<EcCurve> {
+    static SUPPORTED_CURVES: OnceLock<SupportCache<EcCurve>> = OnceLock::new();
+    get_supported(&SUPPORTED_CURVES, sec_level, key_generations::EC_CURVES, |sec_level, curve| {
+        let digest = if curve == EcCurve::CURVE_25519 { Digest::NONE } else { Digest::SHA_2_256 };
+        probe_ec_key_generation(sec_level, curve, digest)
+    })
+}
+
+/// Request a timestamp token for the given challenge from the default secure clock instance.
+/// Returns `Error::SecureClockUnsupported` if the device does not declare a secure clock, e.g.
+/// if the KeyMint backends share a clock with the authenticators.
+pub fn get_timestamp_token(challenge: i64) -> Result<TimeStampToken, Error> {
+    let service_name = format!("{}/default", <BpSecureClock as ISecureClock>::get_descriptor());
+    if !binder::is_declared(&service_name).unwrap_or(false) {
+        return Err(Error::SecureClockUnsupported);
+    }
+
+    let secure_clock = wait_for_interface::<dyn ISecureClock>(&service_name)
+        .expect("Failed to get secure clock service.");
+    key_generations::map_ks_error(secure_clock.generateTimeStamp(challenge))
+}
+
 #[macro_export]
 macro_rules! skip_test_if_no_app_attest_key_feature {
     () => {
@@ -138,6 +407,37 @@ macro_rules! skip_tests_if_keymaster_impl_present {
     };
 }
 
+/// Print the reason for skipping a test, so that skipped tests are distinguishable from passing
+/// ones in the test output. The caller is expected to return right after.
+pub fn log_test_skip(reason: impl std::fmt::Display) {
+    eprintln!("Skipping test: {}.", reason);
+}
+
+/// Returns early from a test if the KeyMint backend does not support the given feature, which
+/// names a field of `KeyMintFeatures`. The backend of the given security level is probed, or the
+/// `TRUSTED_ENVIRONMENT` one if no security level is given, which requires `SecurityLevel` to be
+/// in scope at the call site. The reason for the skip is logged with `log_test_skip`.
+#[macro_export]
+macro_rules! skip_if_unsupported {
+    ($feature:ident) => {
+        $crate::skip_if_unsupported!(SecurityLevel::TRUSTED_ENVIRONMENT, $feature)
+    };
+    ($sec_level:expr, $feature:ident) => {
+        let sec_level = $sec_level;
+        let (version, features) =
+            $crate::keystore2_client_test_utils::get_keymint_version(sec_level);
+        if !features.$feature {
+            $crate::keystore2_client_test_utils::log_test_skip(format!(
+                "KeyMint version {} at {:?} does not support `{}`",
+                version,
+                sec_level,
+                stringify!($feature)
+            ));
+            return;
+        }
+    };
+}
+
 /// Generate EC key and grant it to the list of users with given access vector.
 /// Returns the list of granted keys `nspace` values in the order of given grantee uids.
 pub fn generate_ec_key_and_grant_to_users(
@@ -161,6 +461,35 @@ pub fn generate_ec_key_and_grant_to_users(
     Ok(granted_keys)
 }
 
+/// Indicates whether operation lifecycle logging was requested through `OP_TRACE_ENV_VAR`.
+/// The environment is only consulted once per process.
+fn op_trace_enabled() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+    *ENABLED.get_or_init(|| std::env::var_os(OP_TRACE_ENV_VAR).is_some())
+}
+
+/// Correlation id of an operation, made of the process id and the address of the operation
+/// proxy. It allows to match the log lines of one operation across the helpers.
+fn op_correlation_id(op: &binder::Strong<dyn IKeystoreOperation>) -> String {
+    format!("{}:{:p}", std::process::id(), &**op as *const dyn IKeystoreOperation)
+}
+
+/// Log the result of an operation step if operation lifecycle logging is enabled.
+fn trace_op_step<T>(
+    op: &binder::Strong<dyn IKeystoreOperation>,
+    step: &str,
+    result: &binder::Result<T>,
+) {
+    if op_trace_enabled() {
+        log::info!(
+            "{}: id={} result={:?}",
+            step,
+            op_correlation_id(op),
+            result.as_ref().map(|_| ())
+        );
+    }
+}
+
 /// Generate a EC_P256 key using given domain, namespace and alias.
 /// Create an operation using the generated key and perform sample signing operation.
 pub fn create_signing_operation(
@@ -174,27 +503,61 @@ pub fn create_signing_operation(
     let keystore2 = get_keystore_service();
     let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
 
+    // The returned key descriptor does not carry the alias, so keep it for logging.
+    let trace_alias = if op_trace_enabled() { alias.clone() } else { None };
     let key_metadata =
         key_generations::generate_ec_p256_signing_key(&sec_level, domain, nspace, alias, None)
             .unwrap();
 
-    sec_level.createOperation(
+    let result = sec_level.createOperation(
         &key_metadata.key,
         &authorizations::AuthSetBuilder::new().purpose(op_purpose).digest(op_digest),
         forced_op.0,
-    )
+    );
+
+    if op_trace_enabled() {
+        match &result {
+            Ok(CreateOperationResponse { iOperation: Some(op), .. }) => log::info!(
+                "create: alias={:?} forced={} id={}",
+                trace_alias,
+                forced_op.0,
+                op_correlation_id(op)
+            ),
+            Ok(_) => log::info!("create: alias={:?} returned no operation", trace_alias),
+            Err(e) => log::info!("create: alias={:?} failed: {:?}", trace_alias, e),
+        }
+    }
+    result
+}
+
+/// Get the challenge keystore issued for the operation, which has to be included in the auth
+/// token for keys requiring per-operation authentication. Returns `None` if the operation does
+/// not require a per-operation auth token.
+pub fn extract_operation_challenge(response: &CreateOperationResponse) -> Option<i64> {
+    response.operationChallenge.as_ref().map(|op_challenge| op_challenge.challenge)
 }
 
 /// Performs sample signing operation.
 pub fn perform_sample_sign_operation(
     op: &binder::Strong<dyn IKeystoreOperation>,
 ) -> Result<(), binder::Status> {
-    op.update(b"my message")?;
-    let sig = op.finish(None, None)?;
-    assert!(sig.is_some());
+    let result = op.update(b"my message");
+    trace_op_step(op, "update", &result);
+    result?;
+
+    let sig = op.finish(None, None);
+    trace_op_step(op, "finish", &sig);
+    assert!(sig?.is_some());
     Ok(())
 }
 
+/// Aborts the given operation.
+pub fn abort_operation(op: &binder::Strong<dyn IKeystoreOperation>) -> Result<(), binder::Status> {
+    let result = op.abort();
+    trace_op_step(op, "abort", &result);
+    result
+}
+
 /// Perform sample HMAC sign and verify operations.
 pub fn perform_sample_hmac_sign_verify_op(
     sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
@@ -357,6 +720,14 @@ pub fn get_op_nonce(parameters: &KeyParameters) -> Option<Vec<u8>> {
     None
 }
 
+/// Get DIGEST value from given key parameters list.
+pub fn get_op_digest(parameters: &KeyParameters) -> Option<Digest> {
+    parameters.keyParameter.iter().find_map(|key_param| match (key_param.tag, &key_param.value) {
+        (Tag::DIGEST, KeyParameterValue::Digest(digest)) => Some(*digest),
+        _ => None,
+    })
+}
+
 /// This performs sample encryption operation with given symmetric key (AES/3DES).
 /// It encrypts `SAMPLE_PLAIN_TEXT` of length 128-bits.
 pub fn perform_sample_sym_key_encrypt_op(
@@ -416,17 +787,278 @@ pub fn perform_sample_sym_key_decrypt_op(
     op.finish(Some(input), None)
 }
 
+/// Key sizes which are out of range for every algorithm.
+pub const INVALID_KEY_SIZES: &[i32] = &[0, -1, -256, i32::MIN, i32::MAX];
+
+/// Try to generate keys of the given algorithm with each of `INVALID_KEY_SIZES` and expect each
+/// attempt to fail with `UNSUPPORTED_KEY_SIZE`. Returns the key sizes which deviated from that,
+/// along with the outcome of their key generation. Keys which were unexpectedly generated are
+/// deleted again.
+pub fn assert_invalid_key_size_rejected(
+    keystore2: &binder::Strong<dyn IKeystoreService>,
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    algorithm: Algorithm,
+) -> Vec<(i32, Result<(), Error>)> {
+    let alias = test_alias(&format!("assert_invalid_key_size_rejected_{}", algorithm.0));
+    let base_params = authorizations::AuthSetBuilder::new().no_auth_required().algorithm(algorithm);
+    let base_params = match algorithm {
+        Algorithm::AES | Algorithm::TRIPLE_DES => base_params
+            .purpose(KeyPurpose::ENCRYPT)
+            .purpose(KeyPurpose::DECRYPT)
+            .block_mode(BlockMode::ECB)
+            .padding_mode(PaddingMode::NONE),
+        Algorithm::HMAC => {
+            base_params.purpose(KeyPurpose::SIGN).digest(Digest::SHA_2_256).min_mac_length(128)
+        }
+        Algorithm::RSA => base_params
+            .purpose(KeyPurpose::SIGN)
+            .digest(Digest::SHA_2_256)
+            .padding_mode(PaddingMode::RSA_PKCS1_1_5_SIGN)
+            .rsa_public_exponent(65537),
+        _ => base_params.purpose(KeyPurpose::SIGN).digest(Digest::SHA_2_256),
+    };
+
+    INVALID_KEY_SIZES
+        .iter()
+        .filter_map(|&key_size| {
+            let gen_params = base_params.clone().key_size(key_size);
+            let result = key_generations::map_ks_error(key_generations::generate_key(
+                sec_level,
+                &gen_params,
+                &alias,
+            ));
+            match result {
+                Err(Error::Km(ErrorCode::UNSUPPORTED_KEY_SIZE)) => None,
+                Ok(_) => {
+                    delete_app_key(keystore2, &alias).unwrap();
+                    Some((key_size, Ok(())))
+                }
+                Err(e) => Some((key_size, Err(e))),
+            }
+        })
+        .collect()
+}
+
+/// Error codes with which a backend reports a conformance matrix combination as unsupported.
+const UNSUPPORTED_ERROR_CODES: &[ErrorCode] = &[
+    ErrorCode::UNSUPPORTED_ALGORITHM,
+    ErrorCode::UNSUPPORTED_KEY_SIZE,
+    ErrorCode::UNSUPPORTED_EC_CURVE,
+    ErrorCode::UNSUPPORTED_DIGEST,
+    ErrorCode::UNSUPPORTED_PADDING_MODE,
+    ErrorCode::UNSUPPORTED_BLOCK_MODE,
+    ErrorCode::UNSUPPORTED_MAC_LENGTH,
+];
+
+/// Generate a key with `gen_params`, then create, update with `SAMPLE_PLAIN_TEXT` and finish an
+/// operation with `op_params`. The key is deleted afterwards.
+fn run_conformance_case(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    alias: &str,
+    gen_params: &authorizations::AuthSetBuilder,
+    op_params: &authorizations::AuthSetBuilder,
+) -> ConformanceResult {
+    let keystore2 = get_keystore_service();
+    let result = key_generations::map_ks_error(sec_level.generateKey(
+        &key_generations::make_key_descriptor(Domain::APP, -1, Some(alias.to_string()), None),
+        None,
+        gen_params,
+        0,
+        b"entropy",
+    ))
+    .and_then(|key_metadata| {
+        key_generations::map_ks_error(sec_level.createOperation(
+            &key_metadata.key,
+            op_params,
+            false,
+        ))
+    })
+    .and_then(|op_response| {
+        let op = op_response.iOperation.ok_or(Error::Rc(ResponseCode::SYSTEM_ERROR))?;
+        key_generations::map_ks_error(
+            op.update(SAMPLE_PLAIN_TEXT).and_then(|_| op.finish(None, None)),
+        )
+    });
+    // The key doesn't exist if generation failed.
+    let _ = delete_app_key(&keystore2, alias);
+
+    match result {
+        Ok(_) => ConformanceResult::Pass,
+        Err(Error::Km(e)) if UNSUPPORTED_ERROR_CODES.contains(&e) => {
+            ConformanceResult::Skip(format!("{:?}", e))
+        }
+        Err(e) => ConformanceResult::Fail(format!("{:?}", e)),
+    }
+}
+
+/// Generate keys and perform a sample operation across EC curves, RSA paddings, AES block modes
+/// and HMAC digests, collecting the outcome of every combination into a report.
+pub fn run_conformance_matrix(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+) -> ConformanceReport {
+    let mut cases = Vec::new();
+
+    for ec_curve in [EcCurve::P_224, EcCurve::P_256, EcCurve::P_384, EcCurve::P_521] {
+        for digest in [Digest::NONE, Digest::SHA_2_256] {
+            let gen_params = authorizations::AuthSetBuilder::new()
+                .no_auth_required()
+                .algorithm(Algorithm::EC)
+                .purpose(KeyPurpose::SIGN)
+                .digest(digest)
+                .ec_curve(ec_curve);
+            let op_params =
+                authorizations::AuthSetBuilder::new().purpose(KeyPurpose::SIGN).digest(digest);
+            cases.push((format!("EC {:?} {:?} SIGN", ec_curve, digest), gen_params, op_params));
+        }
+    }
+
+    for (padding, digest) in [
+        (PaddingMode::NONE, Digest::NONE),
+        (PaddingMode::RSA_PKCS1_1_5_SIGN, Digest::NONE),
+        (PaddingMode::RSA_PKCS1_1_5_SIGN, Digest::SHA_2_256),
+        (PaddingMode::RSA_PSS, Digest::SHA_2_256),
+    ] {
+        let gen_params = authorizations::AuthSetBuilder::new()
+            .no_auth_required()
+            .algorithm(Algorithm::RSA)
+            .rsa_public_exponent(65537)
+            .key_size(2048)
+            .purpose(KeyPurpose::SIGN)
+            .padding_mode(padding)
+            .digest(digest);
+        let op_params = authorizations::AuthSetBuilder::new()
+            .purpose(KeyPurpose::SIGN)
+            .padding_mode(padding)
+            .digest(digest);
+        cases.push((format!("RSA 2048 {:?} {:?} SIGN", padding, digest), gen_params, op_params));
+    }
+
+    for key_size in [128, 256] {
+        for (block_mode, padding) in [
+            (BlockMode::ECB, PaddingMode::PKCS7),
+            (BlockMode::CBC, PaddingMode::PKCS7),
+            (BlockMode::CTR, PaddingMode::NONE),
+            (BlockMode::GCM, PaddingMode::NONE),
+        ] {
+            let mut gen_params = authorizations::AuthSetBuilder::new()
+                .no_auth_required()
+                .algorithm(Algorithm::AES)
+                .key_size(key_size)
+                .purpose(KeyPurpose::ENCRYPT)
+                .block_mode(block_mode)
+                .padding_mode(padding);
+            let mut op_params = authorizations::AuthSetBuilder::new()
+                .purpose(KeyPurpose::ENCRYPT)
+                .block_mode(block_mode)
+                .padding_mode(padding);
+            if block_mode == BlockMode::GCM {
+                gen_params = gen_params.min_mac_length(128);
+                op_params = op_params.mac_length(128);
+            }
+            cases.push((
+                format!("AES {} {:?} {:?} ENCRYPT", key_size, block_mode, padding),
+                gen_params,
+                op_params,
+            ));
+        }
+    }
+
+    for digest in [Digest::SHA1, Digest::SHA_2_256, Digest::SHA_2_512] {
+        let gen_params = authorizations::AuthSetBuilder::new()
+            .no_auth_required()
+            .algorithm(Algorithm::HMAC)
+            .key_size(256)
+            .purpose(KeyPurpose::SIGN)
+            .digest(digest)
+            .min_mac_length(128);
+        let op_params = authorizations::AuthSetBuilder::new()
+            .purpose(KeyPurpose::SIGN)
+            .digest(digest)
+            .mac_length(128);
+        cases.push((format!("HMAC 256 {:?} SIGN", digest), gen_params, op_params));
+    }
+
+    let entries = cases
+        .into_iter()
+        .enumerate()
+        .map(|(i, (combination, gen_params, op_params))| {
+            let alias = test_alias(&format!("run_conformance_matrix_{}", i));
+            let result = run_conformance_case(sec_level, &alias, &gen_params, &op_params);
+            ConformanceEntry { combination, result }
+        })
+        .collect();
+    ConformanceReport { entries }
+}
+
+/// Verify that none of the given plaintext markers, e.g. the secret of an imported key, occurs in
+/// the key blob returned for a key with domain BLOB.
+pub fn assert_keyblob_opaque(key_metadata: &KeyMetadata, forbidden_substrings: &[&[u8]]) {
+    let blob = key_metadata.key.blob.as_ref().expect("Key descriptor doesn't carry a key blob.");
+    for forbidden in forbidden_substrings {
+        assert!(!forbidden.is_empty());
+        if let Some(offset) = blob.windows(forbidden.len()).position(|w| w == *forbidden) {
+            panic!("Key blob contains plaintext {:02x?} at offset {}.", forbidden, offset);
+        }
+    }
+}
+
+/// Verify that no certificate or certificate chain is returned for a symmetric key, since
+/// symmetric keys cannot be certified.
+pub fn assert_no_cert_for_symmetric(key_metadata: &KeyMetadata) {
+    assert!(
+        key_metadata.certificate.is_none(),
+        "Unexpected certificate for a symmetric key: {:02x?}",
+        key_metadata.certificate
+    );
+    assert!(
+        key_metadata.certificateChain.is_none(),
+        "Unexpected certificate chain for a symmetric key: {:02x?}",
+        key_metadata.certificateChain
+    );
+}
+
+/// Pull the `KEY_OPERATION_WITH_GENERAL_INFO` atoms from the keystore metrics service and sum up
+/// the operation counts by outcome. The caller requires the `pull_metrics` keystore permission.
+pub fn get_keystore_metrics() -> Result<KeystoreMetrics, Error> {
+    let atoms = key_generations::map_ks_error(
+        get_keystore_metrics_service().pullMetrics(AtomID::KEY_OPERATION_WITH_GENERAL_INFO),
+    )?;
+
+    let mut metrics = KeystoreMetrics::default();
+    for atom in atoms {
+        if let KeystoreAtomPayload::KeyOperationWithGeneralInfo(info) = atom.payload {
+            metrics.operations_total += atom.count;
+            match info.outcome {
+                Outcome::SUCCESS => metrics.operations_succeeded += atom.count,
+                Outcome::ABORT => metrics.operations_aborted += atom.count,
+                Outcome::PRUNED => metrics.operations_pruned += atom.count,
+                Outcome::ERROR => metrics.operations_failed += atom.count,
+                _ => {}
+            }
+        }
+    }
+    Ok(metrics)
+}
+
+/// Build a key alias from the given test name and the uid of the calling process, e.g.
+/// `ks_op_abort_test_10001` for `keystore2_op_abort_test`. Aliases derived from distinct test
+/// names never collide, while each test keeps using the same alias across runs so that keys left
+/// behind by a failed run are overwritten instead of accumulating.
+pub fn test_alias(test_name: &str) -> String {
+    format!("ks_{}_{}", test_name.strip_prefix("keystore2_").unwrap_or(test_name), getuid())
+}
+
 /// Delete a key with domain APP.
 pub fn delete_app_key(
     keystore2: &binder::Strong<dyn IKeystoreService>,
     alias: &str,
 ) -> binder::Result<()> {
-    keystore2.deleteKey(&KeyDescriptor {
-        domain: Domain::APP,
-        nspace: -1,
-        alias: Some(alias.to_string()),
-        blob: None,
-    })
+    keystore2.deleteKey(&key_generations::make_key_descriptor(
+        Domain::APP,
+        -1,
+        Some(alias.to_string()),
+        None,
+    ))
 }
 
 /// Deletes all entries from keystore.
@@ -440,6 +1072,22 @@ pub fn delete_all_entries(keystore2: &binder::Strong<dyn IKeystoreService>) {
     assert!(keystore2.getNumberOfEntries(Domain::APP, -1).unwrap() == 0);
 }
 
+/// Delete all keys in the given namespace using the keystore maintenance `clearNamespace` API.
+/// The caller requires the `clear_uid` keystore permission.
+pub fn clear_namespace(domain: Domain, nspace: i64) -> Result<(), Error> {
+    key_generations::map_ks_error(get_keystore_maintenance_service().clearNamespace(domain, nspace))
+}
+
+/// Verify that no keys are left in the given namespace, as seen by the caller.
+pub fn assert_namespace_cleared(
+    keystore2: &binder::Strong<dyn IKeystoreService>,
+    domain: Domain,
+    nspace: i64,
+) {
+    let remaining = keystore2.getNumberOfEntries(domain, nspace).unwrap();
+    assert_eq!(0, remaining, "{} keys remained in namespace {:?}:{}", remaining, domain, nspace);
+}
+
 /// Encrypt the secure key with given transport key.
 pub fn encrypt_secure_key(
     sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
@@ -482,6 +1130,79 @@ pub fn encrypt_transport_key(
     Ok(encoded.to_vec())
 }
 
+/// Build ASN.1 DER-encoded wrapped key material corresponding to the `SecureKeyWrapper` schema
+/// defined in `IKeyMintDevice.aidl`, wrapping `key_to_wrap` for the given RSA wrapping key:
+///   - A random AES-256 transport key encrypts `key_to_wrap` with AES-GCM, using a random 12 byte
+///     nonce, a 128 bit tag and `auth_params` as additional authenticated data.
+///   - The transport key XORed with the 32 byte `masking_key` is encrypted with the wrapping
+///     public key using RSA-OAEP with SHA-256.
+/// `auth_params` is the DER-encoded `KeyDescription` of the wrapped key. The `KeyDescription`
+/// embedded by `create_wrapped_key` describes an AES-256 ECB/PKCS7 key, so `auth_params` must be
+/// the matching `create_wrapped_key_additional_auth_data` for the import to succeed.
+pub fn build_secure_key_wrapper(
+    key_to_wrap: &[u8],
+    wrapping_public_key: &PKey<Public>,
+    masking_key: &[u8],
+    auth_params: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let mut transport_key = [0; 32];
+    rand_bytes(&mut transport_key).unwrap();
+    let mut nonce = [0; 12];
+    rand_bytes(&mut nonce).unwrap();
+
+    let mut gcm_tag = [0; 16];
+    let encrypted_key = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        &transport_key,
+        Some(&nonce),
+        auth_params,
+        key_to_wrap,
+        &mut gcm_tag,
+    )
+    .unwrap();
+
+    assert_eq!(transport_key.len(), masking_key.len(), "Masking key must be 32 bytes.");
+    let masked_transport_key: Vec<u8> =
+        transport_key.iter().zip(masking_key).map(|(t, m)| t ^ m).collect();
+    let encrypted_transport_key =
+        encrypt_transport_key(&masked_transport_key, wrapping_public_key).unwrap();
+
+    create_wrapped_key(&encrypted_key, &encrypted_transport_key, &nonce, &gcm_tag)
+}
+
+/// Generate an RSA-2048 key with purpose `WRAP_KEY` and `RSA_OAEP` padding with `SHA_2_256`
+/// digest, as required for unwrapping a `SecureKeyWrapper`. Returns the key metadata along with
+/// the DER-encoded public key, which can be used to encrypt the transport key of a wrapped key.
+/// Backends which do not support wrapping keys fail with e.g. `UNSUPPORTED_PURPOSE`, which is
+/// returned as an error.
+pub fn generate_wrapping_key(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    alias: Option<String>,
+) -> Result<(KeyMetadata, Vec<u8>), Error> {
+    let gen_params = authorizations::AuthSetBuilder::new()
+        .no_auth_required()
+        .algorithm(Algorithm::RSA)
+        .digest(Digest::SHA_2_256)
+        .purpose(KeyPurpose::ENCRYPT)
+        .purpose(KeyPurpose::DECRYPT)
+        .purpose(KeyPurpose::WRAP_KEY)
+        .padding_mode(PaddingMode::RSA_OAEP)
+        .key_size(2048)
+        .rsa_public_exponent(65537);
+
+    let key_metadata = key_generations::map_ks_error(sec_level.generateKey(
+        &KeyDescriptor { domain: Domain::APP, nspace: -1, alias, blob: None },
+        None,
+        &gen_params,
+        0,
+        b"entropy",
+    ))?;
+
+    let cert = X509::from_der(key_metadata.certificate.as_ref().unwrap()).unwrap();
+    let public_key_der = cert.public_key().unwrap().public_key_to_der().unwrap();
+    Ok((key_metadata, public_key_der))
+}
+
 /// List aliases using given `startingPastAlias` and verify that the fetched list is matching with
 /// the expected list of aliases.
 pub fn verify_aliases(
@@ -538,6 +1259,33 @@ pub fn get_vsr_api_level() -> i32 {
     }
 }
 
+/// Kill the keystore2 service and wait until init has restarted it and it is registered with the
+/// service manager again. Previously obtained keystore2 binder handles are dead afterwards and
+/// have to be reacquired with `get_keystore_service`.
+/// Note: The tests in this module are run with `--test-threads=1`, so no other test is affected
+/// by the restart.
+pub fn restart_keystore2_service() {
+    let output = Command::new("pidof").arg("keystore2").output().expect("Failed to run pidof.");
+    let id = String::from_utf8(output.stdout).unwrap();
+    let id: String = id.chars().filter(|c| c.is_ascii_digit()).collect();
+    assert!(!id.is_empty(), "keystore2 service is not running.");
+
+    Command::new("kill").arg("-9").arg(&id).status().expect("Failed to kill keystore2.");
+
+    // Loop till keystore2 service is up and running again with a new process id.
+    loop {
+        let output = Command::new("pidof").arg("keystore2").output().expect("Failed to run pidof.");
+        let new_id = String::from_utf8(output.stdout).unwrap();
+        let new_id: String = new_id.chars().filter(|c| c.is_ascii_digit()).collect();
+        if output.status.code() == Some(0) && new_id != id {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    wait_for_interface::<dyn IKeystoreService>("android.system.keystore2.IKeystoreService/default")
+        .expect("keystore2 service did not come back after restart.");
+}
+
 /// Determines whether the SECOND-IMEI can be used as device attest-id.
 pub fn is_second_imei_id_attestation_required(
     keystore2: &binder::Strong<dyn IKeystoreService>,
@@ -569,6 +1317,43 @@ pub fn get_imei(slot: i32) -> Option<Vec<u8>> {
     None
 }
 
+/// Total size in bytes of the certificates returned with the given key, i.e. the leaf certificate
+/// and the rest of the attestation chain. Both are DER-encoded certificates concatenated without
+/// any framing, so their lengths add up to the sum of the individual DER lengths. Keys without
+/// certificates, e.g. symmetric keys, have size `0`.
+pub fn attestation_cert_size(key_metadata: &KeyMetadata) -> usize {
+    [&key_metadata.certificate, &key_metadata.certificateChain]
+        .into_iter()
+        .flatten()
+        .map(|der| der.len())
+        .sum()
+}
+
+/// Find the root of the attestation chain returned with the given key among the given DER-encoded
+/// `SubjectPublicKeyInfo`s of known roots, e.g. the roots used for remote key provisioning.
+/// Returns the index of the matching root, or `None` if the chain terminates at an unknown root.
+pub fn find_attestation_root(
+    key_metadata: &KeyMetadata,
+    expected_roots: &[Vec<u8>],
+) -> Option<usize> {
+    let mut cert_chain: Vec<u8> = Vec::new();
+    cert_chain.extend(key_metadata.certificate.as_ref()?);
+    cert_chain.extend(key_metadata.certificateChain.as_ref()?);
+    let certs = X509::stack_from_der(&cert_chain).expect("Failed to parse certificate chain.");
+    let root_key = certs.last()?.public_key().unwrap().public_key_to_der().unwrap();
+    expected_roots.iter().position(|root| *root == root_key)
+}
+
+/// Verify that the attestation chain returned with the given key terminates at one of the given
+/// known roots, see `find_attestation_root`. Returns the index of the matching root.
+pub fn assert_attestation_chains_to_rkp_root(
+    key_metadata: &KeyMetadata,
+    expected_roots: &[Vec<u8>],
+) -> usize {
+    find_attestation_root(key_metadata, expected_roots)
+        .expect("Attestation chain does not terminate at any of the expected roots.")
+}
+
 /// Get value of the given attestation id.
 pub fn get_attest_id_value(attest_id: Tag, prop_name: &str) -> Option<Vec<u8>> {
     match attest_id {
@@ -604,3 +1389,46 @@ pub fn verify_certificate_serial_num(cert_bytes: &[u8], expected_serial_num: &Bi
     let serial_num = cert.serial_number();
     assert_eq!(serial_num.to_bn().as_ref().unwrap(), expected_serial_num);
 }
+
+/// Parse the leaf certificate of the given key and verify that its subject DN and serial number
+/// equal the requested DER encoded subject and serial number.
+pub fn assert_cert_subject_matches(
+    key_metadata: &KeyMetadata,
+    expected_subject: &[u8],
+    expected_serial: &BigNum,
+) {
+    let cert = X509::from_der(key_metadata.certificate.as_ref().unwrap()).unwrap();
+    let subject = cert.subject_name().to_der().unwrap();
+    let expected_subject_name = X509Name::from_der(expected_subject).unwrap();
+    assert_eq!(
+        subject,
+        expected_subject,
+        "Expected subject {:?}, got {:?}",
+        &*expected_subject_name,
+        cert.subject_name()
+    );
+    let serial = cert.serial_number().to_bn().unwrap();
+    assert_eq!(&serial, expected_serial, "Expected serial {}, got {}", expected_serial, serial);
+}
+
+/// Verify that the validity period of the given DER encoded certificate matches the given dates.
+/// `not_before_ms` and `not_after_ms` are milliseconds since the epoch, as used by the
+/// `CERTIFICATE_NOT_BEFORE` and `CERTIFICATE_NOT_AFTER` tags, and are truncated to whole seconds
+/// before comparing them to the certificate's times.
+pub fn verify_certificate_validity(cert_bytes: &[u8], not_before_ms: i64, not_after_ms: i64) {
+    let cert = X509::from_der(cert_bytes).unwrap();
+    let not_before = Asn1Time::from_unix(not_before_ms / 1000).unwrap();
+    let not_after = Asn1Time::from_unix(not_after_ms / 1000).unwrap();
+    assert!(
+        cert.not_before() == not_before,
+        "Expected not before {}, got {}",
+        not_before,
+        cert.not_before()
+    );
+    assert!(
+        cert.not_after() == not_after,
+        "Expected not after {}, got {}",
+        not_after,
+        cert.not_after()
+    );
+}
@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use nix::unistd::{getuid, Gid, Uid};
+use nix::unistd::{Gid, Uid};
 use rustutils::users::AID_USER_OFFSET;
 
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
@@ -25,6 +25,8 @@ use android_system_keystore2::aidl::android::system::keystore2::{
 
 use keystore2_test_utils::{get_keystore_service, key_generations, key_generations::Error, run_as};
 
+use crate::keystore2_client_test_utils::test_alias;
+
 /// Generate a key and update its public certificate and certificate chain. Test should be able to
 /// load the key and able to verify whether its certificate and cert-chain are updated successfully.
 #[test]
@@ -172,7 +174,7 @@ fn keystore2_update_subcomponent_fails_permission_denied() {
         run_as::run_as(GRANTOR_SU_CTX, Uid::from_raw(0), Gid::from_raw(0), || {
             let keystore2 = get_keystore_service();
             let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
-            let alias = format!("ks_update_subcompo_test_1_{}", getuid());
+            let alias = test_alias("keystore2_update_subcomponent_fails_permission_denied");
             let mut granted_keys = Vec::new();
 
             let key_metadata = key_generations::generate_ec_p256_signing_key(